@@ -0,0 +1,343 @@
+//! Thresholds that can be enabled per axis. These are scaled quantities, except
+//! that firmware with per-axis thresholds uses the top bits of the
+//! characteristic to say which axes the threshold applies to. The rest of the
+//! bits are the magnitude, scaled as in `scaledqty`.
+//!
+//! Older firmware doesn't have the axis bits. It uses the whole characteristic
+//! for the magnitude, and applies the threshold to every axis. So, unless
+//! `ApplicationState::axis_thresholds` says the firmware has them, we don't
+//! look for the axis bits at all. When it does, having none of them set means
+//! every axis, and that's what we write when every axis is enabled.
+//!
+//! See crate::attrs::scaledqty
+
+use axum::extract::rejection::JsonRejection;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+
+use crate::attrs;
+use crate::attrs::scaledqty::{self, ScaledQtyValue, Verification};
+use crate::attrs::uintqty;
+use crate::attrs::{ApplicationState, AttrError, AttributeSpec};
+
+/// The names of the axes, in the order of their bits. The first is the lowest
+/// of the top bits, and the last is the highest.
+static AXES: [&str; 3] = ["x", "y", "z"];
+
+/// The mask with every axis enabled.
+const ALL_AXES: u64 = (1 << AXES.len()) - 1;
+
+/// The response for `GET` requests. The `value` is in `unit` like in a
+/// `ScaledQtyValue`, and `axes` are the names of the axes it applies to, in
+/// order.
+#[derive(Serialize)]
+pub struct AxisQtyValue {
+    pub value: f64,
+    pub unit: String,
+    pub axes: Vec<String>,
+}
+
+/// The body of a `POST` request, like `{"magnitude": 0.5, "unit": "g", "axes":
+/// ["x", "z"]}`. The `magnitude` can also be called `value`, so the body of a
+/// `GET` or a plain `ScaledQtyValue` works too. The `axes` can be left out to
+/// apply to every axis. Unknown fields are rejected, so a misspelled `axes`
+/// isn't silently taken to mean every axis.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AxisQtyRequest {
+    #[serde(alias = "value")]
+    pub magnitude: f64,
+    pub unit: String,
+    #[serde(default)]
+    pub axes: Option<Vec<String>>,
+}
+
+impl From<ScaledQtyValue> for AxisQtyRequest {
+    fn from(value: ScaledQtyValue) -> Self {
+        AxisQtyRequest {
+            magnitude: value.value,
+            unit: value.unit,
+            axes: None,
+        }
+    }
+}
+
+/// How many of the low bits of a characteristic of the given `length` are the
+/// magnitude. That's all of them, unless the firmware has `axis_bits`.
+fn magnitude_bits(length: usize, axis_bits: bool) -> u32 {
+    match axis_bits {
+        true => 8 * length as u32 - AXES.len() as u32,
+        false => 8 * length as u32,
+    }
+}
+
+/// Split a `raw` value into its raw magnitude and the names of the axes it
+/// applies to. Without `axis_bits`, it applies to every axis.
+fn unpack(raw: u64, length: usize, axis_bits: bool) -> (u64, Vec<String>) {
+    let bits = magnitude_bits(length, axis_bits);
+    let mask = match raw.checked_shr(bits).unwrap_or(0) {
+        0 => ALL_AXES,
+        m => m,
+    };
+    let axes = AXES
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| mask & (1 << i) != 0)
+        .map(|(_, a)| String::from(*a))
+        .collect();
+    let magnitude = raw & 1u64.checked_shl(bits).map_or(u64::MAX, |b| b - 1);
+    (magnitude, axes)
+}
+
+/// Get the mask for the axes with the given `names`. Fails if any of them isn't
+/// an axis, if any is listed more than once, or if there are none.
+fn axis_mask(names: &[String]) -> Result<u64, AttrError> {
+    let mut mask = 0;
+    for name in names {
+        let bit = AXES
+            .iter()
+            .position(|a| a == name)
+            .ok_or_else(|| AttrError::OutOfRange(format!("Unknown axis {:?}", name)))?;
+        if mask & (1 << bit) != 0 {
+            return Err(AttrError::OutOfRange(format!(
+                "Axis {:?} is listed more than once",
+                name
+            )));
+        }
+        mask |= 1 << bit;
+    }
+    if mask == 0 {
+        return Err(AttrError::OutOfRange(String::from(
+            "At least one axis must be given",
+        )));
+    }
+    Ok(mask)
+}
+
+/// Convert a `request` to the raw value to write to the attribute `spec`. This
+/// is `scaledqty::encode` for the magnitude, with the axis bits on top if the
+/// firmware has `axis_bits`. If it doesn't, the request has to be for every
+/// axis. The result still has to pass `uintqty::to_bytes`.
+pub fn encode(
+    request: &AxisQtyRequest,
+    spec: &AttributeSpec,
+    axis_bits: bool,
+) -> Result<u64, AttrError> {
+    let mask = match &request.axes {
+        Some(axes) => axis_mask(axes)?,
+        None => ALL_AXES,
+    };
+    if mask != ALL_AXES && !axis_bits {
+        return Err(AttrError::OutOfRange(String::from(
+            "This firmware only supports thresholds on every axis",
+        )));
+    }
+    let magnitude = ScaledQtyValue {
+        value: request.magnitude,
+        unit: request.unit.clone(),
+    };
+    let raw = scaledqty::encode(
+        &magnitude,
+        spec.length,
+        spec.scale.unwrap(),
+        spec.unit.unwrap(),
+        spec.conversions,
+    )?;
+
+    let bits = magnitude_bits(spec.length, axis_bits);
+    if raw.checked_shr(bits).unwrap_or(0) != 0 {
+        return Err(AttrError::OutOfRange(format!(
+            "Magnitude does not fit in {} bits: {} {}",
+            bits, magnitude.value, magnitude.unit
+        )));
+    }
+    // Leave the axis bits clear for every axis, so older firmware understands
+    let mask = if mask == ALL_AXES { 0 } else { mask };
+    Ok(raw | (mask << bits))
+}
+
+/// Convert a `raw` value of the attribute `spec` to what `GET` returns, in the
+/// unit that one of the attribute's unit is `factor` of.
+fn decode(
+    raw: u64,
+    spec: &AttributeSpec,
+    axis_bits: bool,
+    factor: f64,
+    unit: String,
+) -> AxisQtyValue {
+    let (magnitude, axes) = unpack(raw, spec.length, axis_bits);
+    AxisQtyValue {
+        value: scaledqty::from_raw(magnitude, spec.scale.unwrap()) * factor,
+        unit,
+        axes,
+    }
+}
+
+/// Generic method for `GET` requests. This is like `scaledqty::get`, including
+/// converting to the `requested` unit.
+pub async fn get(
+    state: ApplicationState,
+    spec: &'static AttributeSpec,
+    requested: Option<String>,
+) -> Result<Json<AxisQtyValue>, AttrError> {
+    let unit = spec.unit.unwrap();
+
+    // Figure out what unit to return before doing any I/O
+    let requested = requested.unwrap_or_else(|| String::from(unit));
+    let factor =
+        scaledqty::conversion_factor(unit, spec.conversions, &requested).ok_or_else(|| {
+            AttrError::UnitMismatch {
+                expected: Some(String::from(unit)),
+                got: Some(requested.clone()),
+            }
+        })?;
+
+    let raw = uintqty::read(&state, spec.uuid, spec.length, spec.unset_marker).await?;
+    let value = decode(raw, spec, state.axis_thresholds, factor, requested);
+    Ok(Json(value))
+}
+
+/// Get the request for a `POST` method. See `scaledqty::request_from`.
+#[allow(clippy::result_large_err)]
+pub fn request_from(
+    body: Result<Json<AxisQtyRequest>, JsonRejection>,
+    query: &scaledqty::PostQuery,
+) -> Result<AxisQtyRequest, Response> {
+    scaledqty::request_from(body, query)
+}
+
+/// Generic method for `POST` requests. This writes to the attribute's
+/// `write_uuid`, and if `verify` is set, reads back from its `uuid` like
+/// `scaledqty::post` does. The body is then a `Verification` of
+/// `AxisQtyValue`s, in the attribute's own unit.
+pub async fn post(
+    state: ApplicationState,
+    request: AxisQtyRequest,
+    spec: &'static AttributeSpec,
+    verify: bool,
+) -> Result<Response, AttrError> {
+    let raw = encode(&request, spec, state.axis_thresholds)?;
+    let bytes = uintqty::to_bytes(raw, spec.length)?;
+    attrs::write_characteristic(&state, spec.write_uuid(), &bytes).await?;
+    if !verify {
+        return Ok(StatusCode::OK.into_response());
+    }
+
+    let unit = spec.unit.unwrap();
+    let to_value = |v: u64| decode(v, spec, state.axis_thresholds, 1.0, String::from(unit));
+    let (resp, read_back) = match uintqty::verify(&state, spec.uuid, spec.length, raw).await {
+        Ok(v) => (StatusCode::OK, Some(v)),
+        Err(v) => (StatusCode::INTERNAL_SERVER_ERROR, v),
+    };
+    let body = Verification {
+        written: to_value(raw),
+        read_back: read_back.map(to_value),
+    };
+    Ok((resp, Json(body)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+    use serde_json::json;
+
+    use super::*;
+    use crate::attrs::{testing, Kind, ATTRIBUTES};
+
+    fn spec() -> &'static AttributeSpec {
+        ATTRIBUTES.iter().find(|a| a.kind == Kind::Axes).unwrap()
+    }
+
+    fn request(body: serde_json::Value) -> serde_json::Result<AxisQtyRequest> {
+        AxisQtyRequest::deserialize(body)
+    }
+
+    #[test]
+    fn without_axis_bits_the_whole_range_is_the_magnitude() {
+        let spec = spec();
+        // 16 g, the most the accelerometer can be set to
+        let sixteen = request(json!({"value": 16.0, "unit": "g"})).unwrap();
+        assert_eq!(encode(&sixteen, spec, false).unwrap(), 16000);
+        let value = decode(16000, spec, false, 1.0, String::from("g"));
+        assert_eq!(value.value, 16.0);
+        assert_eq!(value.axes, AXES);
+
+        // Every axis is fine, but not just some of them
+        let all = request(json!({"value": 1.0, "unit": "g", "axes": AXES})).unwrap();
+        assert_eq!(encode(&all, spec, false).unwrap(), 1000);
+        let some = request(json!({"value": 1.0, "unit": "g", "axes": ["x"]})).unwrap();
+        assert!(matches!(
+            encode(&some, spec, false),
+            Err(AttrError::OutOfRange(_))
+        ));
+    }
+
+    #[test]
+    fn with_axis_bits_the_top_bits_are_the_axes() {
+        let spec = spec();
+        let some = request(json!({"value": 1.0, "unit": "g", "axes": ["x", "z"]})).unwrap();
+        let raw = encode(&some, spec, true).unwrap();
+        assert_eq!(raw, 0b101 << 13 | 1000);
+        let value = decode(raw, spec, true, 1.0, String::from("g"));
+        assert_eq!(value.value, 1.0);
+        assert_eq!(value.axes, ["x", "z"]);
+
+        // No axis bits means every axis
+        let all = request(json!({"value": 1.0, "unit": "g"})).unwrap();
+        assert_eq!(encode(&all, spec, true).unwrap(), 1000);
+        assert_eq!(unpack(1000, spec.length, true).1, AXES);
+
+        // The magnitude only has 13 bits
+        let sixteen = request(json!({"value": 16.0, "unit": "g"})).unwrap();
+        assert!(encode(&sixteen, spec, true).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_axes() {
+        for axes in [json!(["w"]), json!(["x", "x"]), json!([])] {
+            let r = request(json!({"value": 1.0, "unit": "g", "axes": axes})).unwrap();
+            assert!(encode(&r, spec(), true).is_err(), "{:?}", r.axes);
+        }
+    }
+
+    #[test]
+    fn magnitude_can_be_called_value() {
+        let magnitude = request(json!({"magnitude": 1.0, "unit": "g", "axes": ["x"]})).unwrap();
+        let value = request(json!({"value": 1.0, "unit": "g", "axes": ["x"]})).unwrap();
+        assert_eq!(magnitude.magnitude, value.magnitude);
+        assert_eq!(magnitude.axes, value.axes);
+    }
+
+    #[test]
+    fn rejects_mixed_shapes() {
+        assert!(request(json!({"magnitude": 1.0, "unit": "g", "axis": ["x"]})).is_err());
+        assert!(request(json!({"value": 1.0, "unit": "g", "axis": ["x"]})).is_err());
+        assert!(request(json!({"value": 1.0, "magnitude": 1.0, "unit": "g"})).is_err());
+    }
+
+    #[tokio::test]
+    async fn post_packs_the_magnitude_and_axes() {
+        let ornament = testing::ornament();
+        let mut state = testing::state(ornament.clone());
+        state.axis_thresholds = true;
+        let app = testing::app(state);
+
+        let body = json!({"magnitude": 0.5, "unit": "g", "axes": ["x","z"]});
+        let (status, _) = testing::request(&app, Method::POST, spec().path, Some(body)).await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        // 500 mg, with the x and z bits on top
+        let raw: u16 = 0b101 << 13 | 500;
+        assert_eq!(ornament.value(spec().uuid).unwrap(), raw.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn get_returns_the_value() {
+        let ornament = testing::ornament();
+        ornament.set(spec().uuid, &16000u16.to_be_bytes());
+        let app = testing::app(testing::state(ornament));
+        let (_, body) = testing::request(&app, Method::GET, spec().path, None).await;
+        assert_eq!(body, json!({"value": 16.0, "unit": "g", "axes": AXES}));
+    }
+}
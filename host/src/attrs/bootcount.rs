@@ -0,0 +1,139 @@
+//! Watching the boot count, which only changes when the ornament reboots. The
+//! host keeps track of the last value it saw, and tells anyone listening on
+//! `/bootcount/stream` whenever it changes.
+
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+
+use axum::extract::State;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::attrs;
+use crate::attrs::uintqty;
+use crate::attrs::ApplicationState;
+
+/// How many changes a slow listener can fall behind by before it starts
+/// missing them. Reboots are rare, so this is plenty.
+const CHANGE_CAPACITY: usize = 16;
+
+/// One change to the boot count, meaning the ornament rebooted. This is the
+/// data of each event on `/bootcount/stream`.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct BootCountChange {
+    pub old: u64,
+    pub new: u64,
+}
+
+/// The last boot count we saw, along with everyone listening for changes to it.
+#[derive(Clone)]
+pub struct BootCountWatcher {
+    last: Arc<Mutex<Option<u64>>>,
+    changes: broadcast::Sender<BootCountChange>,
+}
+
+impl Default for BootCountWatcher {
+    fn default() -> Self {
+        BootCountWatcher {
+            last: Default::default(),
+            changes: broadcast::channel(CHANGE_CAPACITY).0,
+        }
+    }
+}
+
+impl BootCountWatcher {
+    /// Record that the boot count is `value`. If we saw a different value
+    /// before, the ornament rebooted, so tell the listeners. The first value we
+    /// see is just remembered.
+    pub fn record(&self, value: u64) {
+        let old = self.last.lock().unwrap().replace(value);
+        match old {
+            Some(old) if old != value => {
+                log::warn!(
+                    "The christmas ornament rebooted: boot count went from {} to {}",
+                    old,
+                    value
+                );
+                // Nobody might be listening, and that's fine
+                let _ = self.changes.send(BootCountChange { old, new: value });
+            }
+            _ => log::debug!("Boot count is {}", value),
+        }
+    }
+
+    /// Record the boot count as notified by the ornament, given the `bytes` of
+    /// the characteristic. Values that aren't set yet are ignored.
+    pub fn record_bytes(&self, bytes: &[u8]) {
+        if bytes.iter().all(|b| *b == 0xff) {
+            log::debug!("Boot count is not set yet");
+            return;
+        }
+        self.record(uintqty::from_bytes(bytes));
+    }
+
+    /// Read the boot count from the ornament and record it. This is for when we
+    /// can't subscribe to it. Failures are logged and otherwise ignored.
+    pub async fn poll(&self, state: &ApplicationState) {
+        match uintqty::read(state, attrs::BOOTCOUNT_UUID, 1, true).await {
+            Ok(v) => self.record(v),
+            Err(e) => log::warn!("Could not read the boot count: {}", e),
+        }
+    }
+}
+
+/// Stream an event every time the boot count changes, as server-sent events.
+/// Each one is a `reboot` event with a `BootCountChange` as its data. This only
+/// sees changes that happen after the client connects. See `reboot_events`.
+pub async fn get_bootcount_stream(
+    State(state): State<ApplicationState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    Sse::new(reboot_events(&state)).keep_alive(KeepAlive::default())
+}
+
+/// The events for `/bootcount/stream`. The stream ends when we start shutting
+/// down, since the server waits for every response to finish first.
+fn reboot_events(state: &ApplicationState) -> impl Stream<Item = Result<Event, Infallible>> {
+    let receiver = state.bootcount.changes.subscribe();
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(change) => {
+                    let event = Event::default()
+                        .event("reboot")
+                        .data(serde_json::to_string(&change).unwrap());
+                    return Some((Ok(event), receiver));
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    log::warn!("Boot count stream missed {} changes", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+    .take_until(state.shutdown.clone().cancelled_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::attrs::testing;
+
+    #[tokio::test]
+    async fn stream_sends_reboots_and_ends_on_shutdown() {
+        let state = testing::state(testing::ornament());
+        let mut events = Box::pin(reboot_events(&state));
+
+        state.bootcount.record(1);
+        state.bootcount.record(2);
+        let next = tokio::time::timeout(Duration::from_secs(1), events.next());
+        assert!(next.await.unwrap().is_some());
+
+        state.shutdown.cancel();
+        let next = tokio::time::timeout(Duration::from_secs(1), events.next());
+        assert!(next.await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,142 @@
+//! Calibrating the light sensor in the field. The scale in `ATTRIBUTES` is from
+//! the datasheet, but every sensor reads a bit differently. So, clients can give
+//! us two reference points, and we fit a line through them. That replaces the
+//! attribute's scale in `/light` reads from then on.
+//!
+//! Calibrations are kept in memory, and also in a file if we were given one, so
+//! they survive restarts.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Deserialize;
+
+use crate::attrs;
+use crate::attrs::scaledqty::Affine;
+use crate::attrs::{ApplicationState, ErrorBody};
+
+/// The path of the attribute `POST /light/calibrate` calibrates.
+const LIGHT_PATH: &str = "/light";
+
+/// The calibrations we have, keyed by attribute path. Attributes that aren't in
+/// here use their scale from `ATTRIBUTES`.
+#[derive(Clone, Default)]
+pub struct Calibrations {
+    current: Arc<Mutex<HashMap<String, Affine>>>,
+    file: Option<PathBuf>,
+}
+
+impl Calibrations {
+    /// Keep calibrations in `file`, starting with whatever is already in it. A
+    /// missing file just means nothing has been calibrated yet.
+    pub fn load(file: PathBuf) -> Result<Self> {
+        let current = match std::fs::read(&file) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("Could not parse calibrations in {:?}", file))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Could not read calibrations in {:?}", file))
+            }
+        };
+        Ok(Calibrations {
+            current: Arc::new(Mutex::new(current)),
+            file: Some(file),
+        })
+    }
+
+    /// Get the calibration for the attribute at `path`, if it has one.
+    pub fn get(&self, path: &str) -> Option<Affine> {
+        self.current.lock().unwrap().get(path).copied()
+    }
+
+    /// Set the calibration for the attribute at `path`, or clear it if `affine`
+    /// is `None`. This takes effect even if it couldn't be saved to the file.
+    fn set(&self, path: &str, affine: Option<Affine>) -> Result<()> {
+        let mut current = self.current.lock().unwrap();
+        match affine {
+            Some(a) => current.insert(String::from(path), a),
+            None => current.remove(path),
+        };
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        std::fs::write(file, serde_json::to_vec_pretty(&*current)?)
+            .with_context(|| format!("Could not save calibrations to {:?}", file))
+    }
+}
+
+/// One reference point. The `raw` value is what the sensor read, as in the
+/// `lsb` of `SmoothedQtyValue`, while the actual light level was `lux`.
+#[derive(Deserialize)]
+pub struct CalibrationPoint {
+    pub raw: f64,
+    pub lux: f64,
+}
+
+/// The body of `POST /light/calibrate`.
+#[derive(Deserialize)]
+pub struct CalibrationRequest {
+    pub points: [CalibrationPoint; 2],
+}
+
+/// Fit a line through the two `points`. Fails if they have the same raw value,
+/// since then there's no line, or if any of them isn't finite.
+fn fit([a, b]: &[CalibrationPoint; 2]) -> Result<Affine, String> {
+    if [a.raw, a.lux, b.raw, b.lux].iter().any(|v| !v.is_finite()) {
+        return Err(String::from("Calibration points must be finite"));
+    }
+    if a.raw == b.raw {
+        return Err(format!(
+            "Calibration points must have different raw values, but both are {}",
+            a.raw
+        ));
+    }
+    let scale = (b.lux - a.lux) / (b.raw - a.raw);
+    Ok(Affine {
+        scale,
+        offset: a.lux - scale * a.raw,
+    })
+}
+
+/// Calibrate `/light` from two reference points. Returns the line we fit, or
+/// `400` if the points don't give one. If we couldn't save it, it's still used,
+/// but we return `500` so the client knows it won't survive a restart.
+pub async fn post_light_calibrate(
+    State(state): State<ApplicationState>,
+    Json(request): Json<CalibrationRequest>,
+) -> Response {
+    let affine = match fit(&request.points) {
+        Ok(a) => a,
+        Err(e) => return attrs::bad_request(e),
+    };
+    log::info!(
+        "Calibrated {} to {} lux per LSB, plus {} lux",
+        LIGHT_PATH,
+        affine.scale,
+        affine.offset
+    );
+    save(&state, Some(affine)).unwrap_or_else(|| Json(affine).into_response())
+}
+
+/// Go back to the datasheet scale for `/light`.
+pub async fn delete_light_calibrate(State(state): State<ApplicationState>) -> Response {
+    log::info!("Cleared the calibration for {}", LIGHT_PATH);
+    save(&state, None).unwrap_or_else(|| StatusCode::OK.into_response())
+}
+
+/// Set the calibration for `/light`, and return the response to send if that
+/// couldn't be saved.
+fn save(state: &ApplicationState, affine: Option<Affine>) -> Option<Response> {
+    let e = state.calibrations.set(LIGHT_PATH, affine).err()?;
+    log::error!("{:?}", e);
+    let body = ErrorBody {
+        error: format!("{:#}", e),
+    };
+    Some((StatusCode::INTERNAL_SERVER_ERROR, Json(body)).into_response())
+}
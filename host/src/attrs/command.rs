@@ -0,0 +1,99 @@
+//! Commands that make the ornament do something, rather than read or change a
+//! value. They're sent by writing an opcode to the command characteristic.
+
+use axum::extract::{Path, State};
+use axum::response::{IntoResponse, Response};
+
+use crate::attrs;
+use crate::attrs::ApplicationState;
+use crate::ble::CharUuid;
+
+/// The write-only command characteristic.
+pub const COMMAND_UUID: CharUuid = CharUuid::short(0x0030);
+
+/// The commands we know about, by name, along with their opcodes.
+static COMMANDS: &[(&str, u8)] = &[
+    // Blink the LEDs, to find out which ornament this is
+    ("identify", 0x01),
+    ("reboot", 0x02),
+];
+
+/// Find the opcode for the command `name`. If `raw` is set, the name can also
+/// be an opcode itself, in decimal or in hex with a leading `0x`. This is for
+/// trying out commands that aren't in the table yet.
+fn opcode(name: &str, raw: bool) -> Option<u8> {
+    if let Some((_, op)) = COMMANDS.iter().find(|(n, _)| *n == name) {
+        return Some(*op);
+    }
+    if !raw {
+        return None;
+    }
+    match name.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => name.parse().ok(),
+    }
+}
+
+/// Send the command `name` to the ornament. Returns `400` if we don't know the
+/// command. Otherwise, the status is that of the write.
+pub async fn post_command(
+    State(state): State<ApplicationState>,
+    Path(name): Path<String>,
+) -> Response {
+    let Some(op) = opcode(&name, state.raw_commands) else {
+        return attrs::bad_request(format!("Unknown command {:?}", name));
+    };
+    log::info!("Sending command {:?} (opcode {:#04x})", name, op);
+    attrs::write_characteristic(&state, COMMAND_UUID, &[op])
+        .await
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+
+    use super::*;
+    use crate::attrs::testing;
+
+    #[tokio::test]
+    async fn named_commands_write_their_opcodes() {
+        for (path, op) in [("/command/identify", 0x01), ("/command/reboot", 0x02)] {
+            let ornament = testing::ornament();
+            let app = testing::app(testing::state(ornament.clone()));
+            let (status, _) = testing::request(&app, Method::POST, path, None).await;
+            assert_eq!(status, StatusCode::OK, "{}", path);
+            assert_eq!(ornament.value(COMMAND_UUID).unwrap(), vec![op], "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_commands_are_rejected() {
+        let ornament = testing::ornament();
+        let app = testing::app(testing::state(ornament.clone()));
+        let (status, body) = testing::request(&app, Method::POST, "/command/dance", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "Unknown command \"dance\"");
+        assert_eq!(ornament.value(COMMAND_UUID).unwrap(), vec![0]);
+    }
+
+    #[tokio::test]
+    async fn raw_opcodes_need_the_flag() {
+        let ornament = testing::ornament();
+        let app = testing::app(testing::state(ornament.clone()));
+        for path in ["/command/0x05", "/command/5"] {
+            let (status, _) = testing::request(&app, Method::POST, path, None).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", path);
+        }
+        assert_eq!(ornament.value(COMMAND_UUID).unwrap(), vec![0]);
+
+        let mut state = testing::state(ornament.clone());
+        state.raw_commands = true;
+        let app = testing::app(state);
+        for (path, op) in [("/command/0x05", 0x05), ("/command/6", 0x06)] {
+            let (status, _) = testing::request(&app, Method::POST, path, None).await;
+            assert_eq!(status, StatusCode::OK, "{}", path);
+            assert_eq!(ornament.value(COMMAND_UUID).unwrap(), vec![op], "{}", path);
+        }
+    }
+}
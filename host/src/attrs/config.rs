@@ -0,0 +1,168 @@
+//! Methods that operate on all of the ornament's configuration attributes at
+//! once, rather than on a single characteristic.
+
+use std::collections::BTreeMap;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+
+use crate::attrs;
+use crate::attrs::axisqty::{self, AxisQtyRequest};
+use crate::attrs::scaledqty::{self, ScaledQtyValue};
+use crate::attrs::uintqty;
+use crate::attrs::{ApplicationState, AttributeSpec, Kind};
+
+/// Reset every configuration attribute back to the "not yet set" state. This is
+/// the same as calling `DELETE` on each of them. The response maps each
+/// attribute's name to whether it was successfully reset. The status is only
+/// `200` if all of them were.
+pub async fn post_reset_config(
+    State(state): State<ApplicationState>,
+) -> (StatusCode, Json<BTreeMap<String, bool>>) {
+    let mut ret = BTreeMap::new();
+    for a in attrs::ATTRIBUTES.iter().filter(|a| a.writable) {
+        let resp = uintqty::delete(state.clone(), a.write_uuid(), a.length).await;
+        ret.insert(attrs::attribute_name(a.path), resp.is_ok());
+    }
+
+    // Only report success if everything succeeded
+    let resp = if ret.values().all(|ok| *ok) {
+        StatusCode::OK
+    } else {
+        log::error!("Could not reset all configuration attributes");
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    (resp, Json(ret))
+}
+
+/// What happened to one of the values in `POST /config`. The `status` is what
+/// `POST` on that attribute alone would have returned, and `error` says why if
+/// it's not `200`.
+#[derive(Serialize)]
+pub struct FieldResult {
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl FieldResult {
+    fn new(status: StatusCode, error: Option<String>) -> Self {
+        FieldResult {
+            status: status.as_u16(),
+            error,
+        }
+    }
+}
+
+/// Set several configuration attributes in one request. The body maps each
+/// attribute's name to the value to set it to, like
+/// `{"light_threshold": {"value": 5, "unit": "lux"}}`. Each value is whatever
+/// `POST` on that attribute alone takes. Attributes that aren't in the body are
+/// left alone.
+///
+/// BLE writes aren't transactional, so this isn't truly atomic. Instead, every
+/// value is validated before any of them is written. If any is invalid, nothing
+/// is written and we return `400`. Otherwise, they're written in order of name,
+/// stopping at the first failure. Values after that get `424`, since they were
+/// never tried.
+///
+/// The response maps each name to its `FieldResult`. The status is `200` if
+/// every write succeeded. If one failed after another succeeded, it's `207`, so
+/// the client knows to check which values stuck. If the very first failed, it's
+/// that failure's status, since nothing changed.
+pub async fn post_config(
+    State(state): State<ApplicationState>,
+    Json(request): Json<BTreeMap<String, serde_json::Value>>,
+) -> (StatusCode, Json<BTreeMap<String, FieldResult>>) {
+    // Validate everything before writing anything
+    let mut writes = Vec::new();
+    let mut ret = BTreeMap::new();
+    for (name, value) in request.iter() {
+        match validate(name, value, state.axis_thresholds) {
+            Ok((spec, bytes)) => writes.push((name, spec, bytes)),
+            Err((status, error)) => {
+                log::error!("Invalid value for {}: {}", name, error);
+                ret.insert(name.clone(), FieldResult::new(status, Some(error)));
+            }
+        }
+    }
+    if !ret.is_empty() {
+        for (name, _, _) in writes {
+            let error = String::from("Not written, since another value was invalid");
+            let result = FieldResult::new(StatusCode::FAILED_DEPENDENCY, Some(error));
+            ret.insert(name.clone(), result);
+        }
+        return (StatusCode::BAD_REQUEST, Json(ret));
+    }
+
+    // Write them in order, stopping at the first failure
+    let mut written = 0;
+    let mut failure = None;
+    for (name, spec, bytes) in writes {
+        if failure.is_some() {
+            let error = String::from("Not written, since an earlier write failed");
+            let result = FieldResult::new(StatusCode::FAILED_DEPENDENCY, Some(error));
+            ret.insert(name.clone(), result);
+            continue;
+        }
+        let write = attrs::write_characteristic(&state, spec.write_uuid(), &bytes).await;
+        let result = match write {
+            Ok(()) => {
+                written += 1;
+                FieldResult::new(StatusCode::OK, None)
+            }
+            Err(e) => {
+                log::error!("Could not write {}: {}", name, e);
+                failure = Some(e.status());
+                FieldResult::new(e.status(), Some(e.to_string()))
+            }
+        };
+        ret.insert(name.clone(), result);
+    }
+
+    let status = match failure {
+        None => StatusCode::OK,
+        Some(_) if written != 0 => StatusCode::MULTI_STATUS,
+        Some(status) => status,
+    };
+    (status, Json(ret))
+}
+
+/// Check that `value` can be written to the configuration attribute called
+/// `name`. Returns the attribute along with the bytes to write, or the status
+/// and error to report for it. See `axisqty::encode` for `axis_bits`.
+fn validate(
+    name: &str,
+    value: &serde_json::Value,
+    axis_bits: bool,
+) -> Result<(&'static AttributeSpec, Vec<u8>), (StatusCode, String)> {
+    let spec = attrs::ATTRIBUTES
+        .iter()
+        .find(|a| a.writable && attrs::attribute_name(a.path) == name)
+        .ok_or_else(|| {
+            let error = format!("No configuration attribute called {:?}", name);
+            (StatusCode::BAD_REQUEST, error)
+        })?;
+    let invalid = |e: serde_json::Error| (StatusCode::BAD_REQUEST, e.to_string());
+    let raw = match spec.kind {
+        Kind::Axes => {
+            let value = AxisQtyRequest::deserialize(value).map_err(invalid)?;
+            axisqty::encode(&value, spec, axis_bits)
+        }
+        _ => {
+            let value = ScaledQtyValue::deserialize(value).map_err(invalid)?;
+            scaledqty::encode(
+                &value,
+                spec.length,
+                spec.scale.unwrap(),
+                spec.unit.unwrap(),
+                spec.conversions,
+            )
+        }
+    }
+    .and_then(|raw| uintqty::to_bytes(raw, spec.length))
+    .map_err(|e| (e.status(), e.to_string()))?;
+    Ok((spec, raw))
+}
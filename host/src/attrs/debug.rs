@@ -0,0 +1,69 @@
+//! Routes for looking at what the ornament actually has, rather than at what we
+//! expect it to have. These are for bringing up new firmware, so they aren't
+//! served unless asked for.
+
+use axum::extract::State;
+use axum::Json;
+use serde::Serialize;
+
+use crate::attrs::{ApplicationState, AttrError};
+use crate::ble;
+
+/// One characteristic on the ornament's service. The `uuid16` is only there if
+/// the UUID is based on the Bluetooth base UUID.
+#[derive(Serialize)]
+pub struct CharacteristicInfo {
+    pub uuid: String,
+    pub uuid16: Option<u16>,
+    pub properties: Vec<&'static str>,
+}
+
+/// List every characteristic on the ornament's service, whether or not we know
+/// about it. This is just what service discovery found, so it doesn't do any
+/// BLE reads. Returns `503` if we're not connected.
+pub async fn get_characteristics(
+    State(state): State<ApplicationState>,
+) -> Result<Json<Vec<CharacteristicInfo>>, AttrError> {
+    let characteristics = state
+        .transport
+        .characteristics()
+        .ok_or_else(|| AttrError::Transport {
+            retry_after: state.transport.retry_after().unwrap_or_default(),
+        })?
+        .into_iter()
+        .map(|c| CharacteristicInfo {
+            uuid: c.uuid.to_string(),
+            uuid16: ble::uuid16_of(c.uuid),
+            properties: c.properties,
+        })
+        .collect();
+    Ok(Json(characteristics))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+
+    use crate::attrs::testing;
+
+    #[tokio::test]
+    async fn lists_characteristics_from_transport() {
+        let ornament = testing::ornament();
+        let app = testing::app(testing::state(ornament.clone()));
+        let path = "/debug/characteristics";
+
+        let (status, body) = testing::request(&app, Method::GET, path, None).await;
+        assert_eq!(status, StatusCode::OK);
+        let heap = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|c| c["uuid16"] == 0x0002)
+            .unwrap();
+        assert_eq!(heap["properties"], serde_json::json!(["read"]));
+
+        ornament.set_connected(false);
+        let (status, _) = testing::request(&app, Method::GET, path, None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,149 @@
+//! Everything that can go wrong with an attribute, and what we tell the client
+//! when it does. This is the only place that decides the status code for each
+//! failure, so every route agrees on them.
+
+use std::fmt;
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+
+use crate::attrs;
+use crate::ble::CharUuid;
+
+/// How long clients should wait before reading a value again, if it hasn't been
+/// set yet. The ornament sets its values soon after booting, so this is short.
+const UNSET_RETRY_AFTER: Duration = Duration::from_secs(2);
+
+/// Why an attribute couldn't be read or written.
+#[derive(Debug)]
+pub enum AttrError {
+    /// The ornament doesn't have the characteristic. This is `404`.
+    NotFound { uuid: CharUuid },
+    /// Reading the characteristic failed. This is `500`.
+    Unreadable { uuid: CharUuid },
+    /// Writing the characteristic failed. This is `500`.
+    Unwritable { uuid: CharUuid },
+    /// The characteristic isn't the length we expect. This is `500`, and the
+    /// body says what the lengths were.
+    BadLength {
+        uuid: CharUuid,
+        expected: usize,
+        actual: usize,
+    },
+    /// The characteristic is on a standard service the ornament doesn't have,
+    /// like with older firmware. This is `503` without `Retry-After`, so
+    /// clients know to fall back to something else.
+    Unavailable { uuid: CharUuid },
+    /// The ornament hasn't set the value yet. This is `503` with a short
+    /// `Retry-After`.
+    Unset { uuid: CharUuid },
+    /// We can't reach the ornament right now, like while reconnecting. This is
+    /// `503` with `Retry-After` set to `retry_after`.
+    Transport { retry_after: Duration },
+    /// The request was in a unit we can't convert from or to. This is `400`.
+    UnitMismatch {
+        expected: Option<String>,
+        got: Option<String>,
+    },
+    /// The value can't be written to the characteristic. This is `400`.
+    OutOfRange(String),
+}
+
+impl AttrError {
+    /// The characteristic this is about, if any.
+    fn uuid(&self) -> Option<CharUuid> {
+        match self {
+            AttrError::NotFound { uuid }
+            | AttrError::Unreadable { uuid }
+            | AttrError::Unwritable { uuid }
+            | AttrError::BadLength { uuid, .. }
+            | AttrError::Unavailable { uuid }
+            | AttrError::Unset { uuid } => Some(*uuid),
+            _ => None,
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AttrError::NotFound { .. } => StatusCode::NOT_FOUND,
+            AttrError::Unreadable { .. }
+            | AttrError::Unwritable { .. }
+            | AttrError::BadLength { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            AttrError::Unavailable { .. }
+            | AttrError::Unset { .. }
+            | AttrError::Transport { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            AttrError::UnitMismatch { .. } | AttrError::OutOfRange(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl fmt::Display for AttrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttrError::NotFound { uuid } => {
+                write!(f, "Could not find characteristic {}", uuid)
+            }
+            AttrError::Unreadable { uuid } => {
+                write!(f, "Could not read characteristic {}", uuid)
+            }
+            AttrError::Unwritable { uuid } => {
+                write!(f, "Could not write characteristic {}", uuid)
+            }
+            AttrError::BadLength {
+                uuid,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Characteristic {} has the wrong length: expected {} bytes, but got {}",
+                uuid, expected, actual
+            ),
+            AttrError::Unavailable { uuid } => {
+                write!(
+                    f,
+                    "Characteristic {} is not supported by this firmware",
+                    uuid
+                )
+            }
+            AttrError::Unset { uuid } => {
+                write!(f, "Characteristic {} has not been set yet", uuid)
+            }
+            AttrError::Transport { .. } => write!(f, "Not connected to the christmas ornament"),
+            AttrError::UnitMismatch { expected, got } => {
+                write!(f, "Expected unit {:?}, but got {:?}", expected, got)
+            }
+            AttrError::OutOfRange(why) => f.write_str(why),
+        }
+    }
+}
+
+/// The errors are logged here, so the places that make them don't have to.
+impl IntoResponse for AttrError {
+    fn into_response(self) -> Response {
+        match self.uuid() {
+            Some(uuid) => log::error!(uuid:% = uuid; "{}", self),
+            None => log::error!("{}", self),
+        }
+
+        let mut body = serde_json::json!({ "error": self.to_string() });
+        let status = self.status();
+        let retry_after = match self {
+            AttrError::BadLength {
+                expected, actual, ..
+            } => {
+                body["expected"] = expected.into();
+                body["actual"] = actual.into();
+                None
+            }
+            AttrError::Unset { .. } => Some(UNSET_RETRY_AFTER),
+            AttrError::Transport { retry_after } => Some(retry_after),
+            _ => None,
+        };
+        match retry_after {
+            Some(d) => (status, attrs::retry_after(d), Json(body)).into_response(),
+            None => (status, Json(body)).into_response(),
+        }
+    }
+}
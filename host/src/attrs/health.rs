@@ -0,0 +1,79 @@
+//! Liveness checks and recovery for the bridge between the host and the
+//! christmas ornament. Unlike the other attributes, these are about whether we
+//! can talk to the ornament at all, rather than about any value on it.
+
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+
+use crate::attrs;
+use crate::attrs::status;
+use crate::attrs::ApplicationState;
+
+/// How long to wait for the ornament to respond before declaring it unhealthy.
+/// This should be short, since health checks are polled frequently.
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long clients should wait before checking again, if we're connected but
+/// the ornament didn't answer.
+const HEALTH_RETRY_AFTER: Duration = Duration::from_secs(2);
+
+/// Check that we can actually read from the ornament right now. We read the boot
+/// count since it's only one byte. Returns `200` if that worked, and `503`
+/// otherwise. The `503` has `Retry-After` set to when the next attempt to
+/// reconnect starts if we're reconnecting, and to a couple of seconds if not.
+pub async fn get_healthz(State(state): State<ApplicationState>) -> Response {
+    let read = attrs::read_characteristic(&state, attrs::BOOTCOUNT_UUID);
+    match tokio::time::timeout(HEALTH_TIMEOUT, read).await {
+        Ok(Ok(_)) => return StatusCode::OK.into_response(),
+        Ok(Err(e)) => log::error!("Health check failed: {}", e),
+        Err(_) => log::error!("Health check failed: timed out reading from the ornament"),
+    }
+    let retry_after = state.transport.retry_after().unwrap_or(HEALTH_RETRY_AFTER);
+    attrs::service_unavailable(retry_after)
+}
+
+/// Drop the connection to the ornament and connect again from scratch, scanning
+/// for it. This is for when the ornament moved. Other requests get `503` in the
+/// meantime. Returns `200` with the new `Status`, or `504` if we couldn't
+/// connect. In that case, we keep trying in the background.
+///
+/// Connecting can take up to the connect timeout, so the request timeout
+/// should be longer than that if the response matters.
+pub async fn post_reconnect(State(state): State<ApplicationState>) -> Response {
+    match state.transport.reconnect().await {
+        Ok(()) => (StatusCode::OK, Json(status::current(&state).await)).into_response(),
+        Err(e) => {
+            log::error!("Could not reconnect to the christmas ornament: {:?}", e);
+            StatusCode::GATEWAY_TIMEOUT.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+
+    use crate::attrs::testing;
+
+    #[tokio::test]
+    async fn healthz_follows_transport() {
+        let ornament = testing::ornament();
+        ornament.set(crate::attrs::BOOTCOUNT_UUID, &[3]);
+        let app = testing::app(testing::state(ornament.clone()));
+        let (status, _) = testing::request(&app, Method::GET, "/healthz", None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        ornament.set_connected(false);
+        let (status, _) = testing::request(&app, Method::GET, "/healthz", None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        // Reconnecting goes through the transport too
+        let (status, body) = testing::request(&app, Method::POST, "/reconnect", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["connected"], true);
+    }
+}
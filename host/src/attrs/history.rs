@@ -0,0 +1,87 @@
+//! A short history of the ornament's battery voltage, kept in memory. It's
+//! sampled in the background, so we can see which way the battery is going
+//! without logging it anywhere.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::extract::State;
+use axum::Json;
+use serde::Serialize;
+
+use crate::attrs;
+use crate::attrs::scaledqty;
+use crate::attrs::uintqty;
+use crate::attrs::ApplicationState;
+
+/// The path of the attribute we keep the history of.
+const BATTERY_PATH: &str = "/battery";
+
+/// One battery reading. The `timestamp` is when it was taken, in seconds since
+/// the UNIX epoch.
+#[derive(Clone, Copy, Serialize)]
+pub struct BatterySample {
+    pub timestamp: u64,
+    pub volts: f64,
+}
+
+/// The most recent battery readings, oldest first. Once it holds `capacity`
+/// of them, the oldest is dropped to make room for each new one.
+#[derive(Clone)]
+pub struct BatteryHistory {
+    samples: Arc<Mutex<VecDeque<BatterySample>>>,
+    capacity: usize,
+}
+
+impl BatteryHistory {
+    pub fn new(capacity: usize) -> Self {
+        BatteryHistory {
+            samples: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// Record a new reading of `volts`, taken now.
+    pub fn push(&self, volts: f64) {
+        if self.capacity == 0 {
+            return;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == self.capacity {
+            samples.pop_front();
+        }
+        samples.push_back(BatterySample { timestamp, volts });
+    }
+
+    /// Take a copy of all the readings, oldest first.
+    pub fn snapshot(&self) -> Vec<BatterySample> {
+        self.samples.lock().unwrap().iter().copied().collect()
+    }
+}
+
+/// Read the battery voltage and add it to the history. Read failures are logged
+/// and skipped, so they leave a gap in the history.
+pub async fn sample_battery(state: &ApplicationState) {
+    let spec = attrs::ATTRIBUTES
+        .iter()
+        .find(|a| a.path == BATTERY_PATH)
+        .expect("The battery should be an attribute");
+    match uintqty::read(state, spec.uuid, spec.length, spec.unset_marker).await {
+        Ok(raw) => state
+            .battery_history
+            .push(scaledqty::from_raw(raw, spec.scale.unwrap())),
+        Err(e) => log::warn!("Could not sample the battery: {}", e),
+    }
+}
+
+/// Return the battery history, oldest first. This doesn't do any BLE reads.
+pub async fn get_battery_history(
+    State(state): State<ApplicationState>,
+) -> Json<Vec<BatterySample>> {
+    Json(state.battery_history.snapshot())
+}
@@ -0,0 +1,111 @@
+//! Metrics about the host process itself, served at `/host/status`. These are
+//! about the bridge, not the ornament, so they never touch BLE. For the
+//! ornament's own heap and sensors, see `ATTRIBUTES` and `/status`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Json;
+use serde::Serialize;
+
+use crate::attrs::ApplicationState;
+use crate::transport::ConnectionState;
+
+/// Counters for the host process, shared between everything that updates them.
+/// The process's uptime counts from when these are created.
+#[derive(Clone)]
+pub struct HostMetrics {
+    started: Instant,
+    requests: Arc<AtomicU64>,
+}
+
+impl Default for HostMetrics {
+    fn default() -> Self {
+        HostMetrics {
+            started: Instant::now(),
+            requests: Default::default(),
+        }
+    }
+}
+
+/// Count every request the server handles, and log it once it's done. This goes
+/// around the whole app, so requests we make to our own router, like when
+/// refreshing the cache, aren't counted.
+pub async fn count_request(
+    State(state): State<ApplicationState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+    let response = next.run(request).await;
+    state.metrics.requests.fetch_add(1, Ordering::Relaxed);
+    log::debug!("{} {} -> {}", method, path, response.status());
+    response
+}
+
+/// The response for `GET /host/status`. The `uptime_s` is how long the process
+/// has been running, and `requests` is how many HTTP requests it has served.
+/// The `reconnects` are how many times we've connected again after the first
+/// time, whether on our own or on request.
+#[derive(Serialize)]
+pub struct HostStatus {
+    pub uptime_s: u64,
+    pub requests: u64,
+    pub connection: ConnectionState,
+    pub reconnects: u64,
+}
+
+/// Report the `HostStatus`. This doesn't do any BLE reads.
+pub async fn get_host_status(State(state): State<ApplicationState>) -> Json<HostStatus> {
+    let connection = state.transport.state().await;
+    Json(HostStatus {
+        uptime_s: state.metrics.started.elapsed().as_secs(),
+        requests: state.metrics.requests.load(Ordering::Relaxed),
+        connection,
+        reconnects: state.transport.reconnects(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::{Method, StatusCode};
+
+    use super::*;
+    use crate::attrs::testing;
+
+    #[tokio::test]
+    async fn reports_connection_from_transport() {
+        let ornament = testing::ornament();
+        let app = testing::app(testing::state(ornament.clone()));
+
+        let (status, body) = testing::request(&app, Method::GET, "/host/status", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["connection"], "connected");
+        assert_eq!(body["reconnects"], 0);
+
+        ornament.set_connected(false);
+        let (_, body) = testing::request(&app, Method::GET, "/host/status", None).await;
+        assert_eq!(body["connection"], "reconnecting");
+    }
+
+    #[tokio::test]
+    async fn counts_uptime_and_requests() {
+        let mut state = testing::state(testing::ornament());
+        state.metrics.started = Instant::now() - Duration::from_secs(60);
+        let app = testing::app(state.clone())
+            .layer(axum::middleware::from_fn_with_state(state, count_request));
+
+        testing::request(&app, Method::GET, "/host/status", None).await;
+        let (_, body) = testing::request(&app, Method::GET, "/host/status", None).await;
+        assert!(body["uptime_s"].as_u64().unwrap() >= 60);
+        // Requests are counted once they're done
+        assert_eq!(body["requests"], 1);
+    }
+}
@@ -3,121 +3,670 @@
 //! actual BLE characteristics. Here, we implement the logic for `GET` and
 //! `POST` requests.
 
+mod axisqty;
+mod bootcount;
+mod calibrate;
+mod command;
+mod config;
+mod debug;
+mod error;
+mod health;
+mod history;
+mod host;
 mod scaledqty;
+mod smoothed;
+mod status;
+#[cfg(test)]
+pub mod testing;
 mod uintqty;
+mod ws;
 
-use axum::http::StatusCode;
-use axum::routing::{get, post};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderName, Method, Request, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post, MethodRouter};
 use axum::{Json, Router};
-use btleplug::api::Service;
-use btleplug::platform::Peripheral;
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+use tower::ServiceExt;
 
 use crate::ble;
+use crate::ble::CharUuid;
+use crate::transport::{OrnamentTransport, TransportError};
+
+pub use bootcount::BootCountWatcher;
+pub use calibrate::Calibrations;
+pub use error::AttrError;
+pub use history::{sample_battery, BatteryHistory};
+pub use host::{count_request, HostMetrics};
+pub use smoothed::RollingAverage;
+pub use status::{refresh_sensors, SensorCache};
+
+/// The boot count characteristic. It's only one byte, so it's cheap to read.
+pub const BOOTCOUNT_UUID: CharUuid = CharUuid::short(0x0010);
 
 /// The objects each method requires to do its job.
 #[derive(Clone)]
 pub struct ApplicationState {
-    pub peripheral: Peripheral,
-    pub service: Service,
+    /// What the attributes are read from and written to, and how we're
+    /// connected to the ornament.
+    pub transport: Arc<dyn OrnamentTransport>,
+    pub cache: SensorCache,
+    pub bootcount: BootCountWatcher,
+    pub battery_history: BatteryHistory,
+    pub averages: RollingAverage,
+    pub calibrations: Calibrations,
+    pub metrics: HostMetrics,
+    /// Whether `POST /command/:name` accepts opcodes that aren't in its table.
+    pub raw_commands: bool,
+    /// Whether the routes that change anything are left out. See `router`.
+    pub read_only: bool,
+    /// Whether to accept characteristics that are longer than we expect. See
+    /// `uintqty::read`.
+    pub lenient_length: bool,
+    /// Whether the firmware keeps an axis mask in the top bits of `Kind::Axes`
+    /// attributes. See `axisqty`.
+    pub axis_thresholds: bool,
+    /// How long to spend handling a request before giving up on it, with a
+    /// `504`. Streams aren't bound by it.
+    pub request_timeout: Duration,
+    /// Cancelled when we start shutting down. Responses that would otherwise
+    /// stay open, like streams, end then.
+    pub shutdown: CancellationToken,
 }
 
-/// Create a new router that handles all of the attribute routes. Modify this if
-/// a new attribute is added.
-pub fn router() -> Router<ApplicationState> {
-    Router::new()
-        .route("/heap", get(get_heap))
-        .route("/battery", get(get_battery))
-        .route("/bootcount", get(get_bootcount))
-        .route("/light", get(get_light))
-        .route("/light/threshold", get(get_light_threshold))
-        .route("/light/threshold", post(post_light_threshold))
-        .route("/accelerometer", get(get_accelerometer))
-        .route("/accelerometer/threshold", get(get_accelerometer_threshold))
-        .route(
-            "/accelerometer/threshold",
-            post(post_accelerometer_threshold),
-        )
+/// The body of an error response, for when we have more to say than just the
+/// status code.
+#[derive(Serialize)]
+pub struct ErrorBody {
+    pub error: String,
 }
 
-/// Utility method for the common task of reading a characteristic and returning
-/// its bytes, given its 16-bit UUID.
-pub async fn read_characteristic<T>(
-    state: &ApplicationState,
-    uuid16: u16,
-) -> Result<Vec<u8>, (StatusCode, Json<Option<T>>)> {
-    // First, convert the 16-bit UUID to a 128-bit UUID
-    let uuid = ble::uuid_16(uuid16);
-    log::info!("Reading characteristic with UUID16 {:04x}", uuid16);
-
-    // Then, get the characteristic from the service
-    let characteristic = match ble::find_characteristic(&state.service, uuid) {
-        Some(c) => {
-            log::debug!("    successfully found characteristic");
-            c
-        }
-        None => {
-            log::error!("Could not find characteristic with UUID16 {:04x}", uuid16);
-            return Err((StatusCode::NOT_FOUND, Json(None)));
+/// The `Retry-After` header, telling the client to wait for `duration` before
+/// trying again. It only has a resolution of seconds, so we round up.
+pub fn retry_after(duration: Duration) -> [(HeaderName, String); 1] {
+    let seconds = duration.as_secs() + u64::from(duration.subsec_nanos() != 0);
+    [(header::RETRY_AFTER, seconds.to_string())]
+}
+
+/// Utility method for returning a `SERVICE_UNAVAILABLE` with `retry_after`.
+pub fn service_unavailable(duration: Duration) -> Response {
+    (StatusCode::SERVICE_UNAVAILABLE, retry_after(duration)).into_response()
+}
+
+/// Utility method for returning a `BAD_REQUEST` with a message explaining why.
+/// The message is also logged.
+pub fn bad_request(error: String) -> Response {
+    log::error!("{}", error);
+    (StatusCode::BAD_REQUEST, Json(ErrorBody { error })).into_response()
+}
+
+/// The body of a `404`. Clients that got the path wrong probably want to know
+/// what the right ones are, so the `hint` says where to look.
+#[derive(Serialize)]
+pub struct NotFoundBody {
+    pub error: String,
+    pub hint: &'static str,
+}
+
+/// Fallback for paths that don't have a route.
+async fn not_found(method: Method, uri: Uri) -> (StatusCode, Json<NotFoundBody>) {
+    let error = format!("No route for {} {}", method, uri.path());
+    log::warn!("{}", error);
+    let body = NotFoundBody {
+        error,
+        hint: "GET /attributes lists the attributes and the methods each supports",
+    };
+    (StatusCode::NOT_FOUND, Json(body))
+}
+
+/// Fallback for paths that have a route, but not for this method. The `Allow`
+/// header is added for us, from the methods that path does have. For
+/// attributes, those come from whether they're readable and writable.
+async fn method_not_allowed(method: Method, uri: Uri) -> (StatusCode, Json<ErrorBody>) {
+    let error = format!("{} is not supported on {}", method, uri.path());
+    log::warn!("{}", error);
+    (StatusCode::METHOD_NOT_ALLOWED, Json(ErrorBody { error }))
+}
+
+/// The kind of value an attribute holds, which decides its JSON schema.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// A `UIntQtyValue`. These can't be writable.
+    UInt,
+    /// A `ScaledQtyValue`. These must have a `scale` and a `unit`.
+    Scaled,
+    /// A `SmoothedQtyValue`, for noisy sensors. These are like `Scaled`, but
+    /// can't be writable.
+    Smoothed,
+    /// An `AxisQtyValue`, for thresholds that can be enabled per axis. These
+    /// are like `Scaled`, and their magnitude is scaled the same way.
+    Axes,
+}
+
+/// Everything about an attribute. This is also what `GET /attributes` returns.
+///
+/// Reads come from `uuid`. Writes go to `write_uuid` if it's set, since
+/// configuration characteristics come in pairs, and to `uuid` otherwise. When
+/// verifying a write, we read back from `uuid`. The ornament's own
+/// characteristics are `CharUuid::short`, but attributes from other services
+/// can use any `CharUuid`.
+#[derive(Serialize)]
+pub struct AttributeSpec {
+    pub path: &'static str,
+    pub uuid: CharUuid,
+    pub write_uuid: Option<CharUuid>,
+    pub kind: Kind,
+    pub length: usize,
+    /// Whether all 0xff bytes means the value isn't set yet, rather than being
+    /// a real reading. Sensors that can legitimately read that high turn this
+    /// off. Writable attributes need it, since that's what `DELETE` writes.
+    pub unset_marker: bool,
+    pub scale: Option<f64>,
+    pub unit: Option<&'static str>,
+    pub conversions: scaledqty::Conversions,
+    pub readable: bool,
+    pub writable: bool,
+    /// Whether the characteristic is on one of the standard services rather
+    /// than the ornament's own. Only newer firmware has those, so it's not
+    /// expected at startup, and it's `503` rather than `404` if it's missing.
+    pub standard: bool,
+}
+
+impl AttributeSpec {
+    /// The characteristic writes go to.
+    pub fn write_uuid(&self) -> CharUuid {
+        self.write_uuid.unwrap_or(self.uuid)
+    }
+}
+
+/// All of the attributes. This is the single place they're listed, so modify
+/// this if a new attribute is added.
+pub static ATTRIBUTES: &[AttributeSpec] = &[
+    AttributeSpec {
+        path: "/heap",
+        uuid: CharUuid::short(0x0002),
+        write_uuid: None,
+        kind: Kind::UInt,
+        length: 4,
+        unset_marker: true,
+        scale: None,
+        unit: Some("bytes"),
+        conversions: &[],
+        readable: true,
+        writable: false,
+        standard: false,
+    },
+    AttributeSpec {
+        path: "/battery",
+        uuid: CharUuid::short(0x0003),
+        write_uuid: None,
+        kind: Kind::Scaled,
+        length: 2,
+        unset_marker: true,
+        scale: Some(1.00709544518e-4),
+        unit: Some("volts"),
+        conversions: &[],
+        readable: true,
+        writable: false,
+        standard: false,
+    },
+    AttributeSpec {
+        path: "/battery/level",
+        uuid: ble::BATTERY_LEVEL_UUID,
+        write_uuid: None,
+        kind: Kind::UInt,
+        length: 1,
+        unset_marker: true,
+        scale: None,
+        unit: Some("%"),
+        conversions: &[],
+        readable: true,
+        writable: false,
+        standard: true,
+    },
+    AttributeSpec {
+        path: "/light",
+        uuid: CharUuid::short(0x0004),
+        write_uuid: None,
+        kind: Kind::Smoothed,
+        length: 4,
+        unset_marker: true,
+        scale: Some(1e-3),
+        unit: Some("lux"),
+        conversions: &[],
+        readable: true,
+        writable: false,
+        standard: false,
+    },
+    AttributeSpec {
+        path: "/accelerometer",
+        uuid: CharUuid::short(0x0005),
+        write_uuid: None,
+        kind: Kind::UInt,
+        length: 3,
+        unset_marker: true,
+        scale: None,
+        unit: None,
+        conversions: &[],
+        readable: true,
+        writable: false,
+        standard: false,
+    },
+    AttributeSpec {
+        path: "/light/threshold",
+        uuid: CharUuid::short(0x0006),
+        write_uuid: Some(CharUuid::short(0x0008)),
+        kind: Kind::Scaled,
+        length: 4,
+        unset_marker: true,
+        scale: Some(1e-1),
+        unit: Some("lux"),
+        conversions: &[],
+        readable: true,
+        writable: true,
+        standard: false,
+    },
+    AttributeSpec {
+        path: "/accelerometer/threshold",
+        uuid: CharUuid::short(0x0007),
+        write_uuid: Some(CharUuid::short(0x0009)),
+        kind: Kind::Axes,
+        length: 2,
+        unset_marker: true,
+        scale: Some(1e-3),
+        unit: Some("g"),
+        conversions: scaledqty::G_CONVERSIONS,
+        readable: true,
+        writable: true,
+        standard: false,
+    },
+    AttributeSpec {
+        path: "/bootcount",
+        uuid: BOOTCOUNT_UUID,
+        write_uuid: None,
+        kind: Kind::UInt,
+        length: 1,
+        unset_marker: true,
+        scale: None,
+        unit: None,
+        conversions: &[],
+        readable: true,
+        writable: false,
+        standard: false,
+    },
+];
+
+// Check the attributes at compile time, so a bad entry doesn't build
+const _: () = {
+    let mut i = 0;
+    while i < ATTRIBUTES.len() {
+        let spec = &ATTRIBUTES[i];
+        assert!(spec.length != 0);
+        assert!(spec.length <= 8);
+        assert!(spec.unset_marker || !spec.writable);
+        assert!(!spec.standard || !spec.writable);
+        match spec.kind {
+            Kind::UInt => {
+                assert!(spec.scale.is_none());
+                assert!(spec.conversions.is_empty());
+                assert!(!spec.writable);
+            }
+            Kind::Scaled | Kind::Smoothed | Kind::Axes => {
+                assert!(!matches!(spec.kind, Kind::Smoothed) || !spec.writable);
+                assert!(spec.unit.is_some());
+                match spec.scale {
+                    Some(scale) => assert!(scale > 0.0 && scale < f64::INFINITY),
+                    None => panic!("Scaled attributes must have a scale"),
+                }
+            }
         }
+        i += 1;
+    }
+};
+
+/// The UUIDs of all the characteristics we expect the ornament's own service to
+/// have.
+pub fn expected_uuids() -> Vec<CharUuid> {
+    ATTRIBUTES
+        .iter()
+        .filter(|a| !a.standard)
+        .flat_map(|a| std::iter::once(a.uuid).chain(a.write_uuid))
+        .collect()
+}
+
+/// List all of the attributes, and how to use them.
+async fn get_attributes() -> Json<&'static [AttributeSpec]> {
+    Json(ATTRIBUTES)
+}
+
+/// Build the methods that handle the route for the attribute `spec`. `GET`
+/// reads it, and `POST` and `DELETE` write it. If `read_only` is set, only `GET`
+/// is there.
+fn methods(spec: &'static AttributeSpec, read_only: bool) -> MethodRouter<ApplicationState> {
+    let unit = spec.unit.map(String::from);
+    let mut methods = MethodRouter::new();
+
+    if spec.readable {
+        methods = match spec.kind {
+            Kind::UInt => methods.get(move |State(state): State<ApplicationState>| {
+                uintqty::get(state, spec.uuid, spec.length, spec.unset_marker, unit)
+            }),
+            Kind::Scaled => methods.get(
+                move |State(state): State<ApplicationState>,
+                      Query(query): Query<scaledqty::UnitQuery>| {
+                    scaledqty::get(
+                        state,
+                        spec.uuid,
+                        spec.length,
+                        spec.unset_marker,
+                        spec.scale.unwrap(),
+                        unit.unwrap(),
+                        spec.conversions,
+                        query.unit,
+                    )
+                },
+            ),
+            Kind::Smoothed => methods.get(
+                move |State(state): State<ApplicationState>,
+                      Query(query): Query<scaledqty::UnitQuery>| {
+                    smoothed::get(state, spec, query.unit)
+                },
+            ),
+            Kind::Axes => methods.get(
+                move |State(state): State<ApplicationState>,
+                      Query(query): Query<scaledqty::UnitQuery>| {
+                    axisqty::get(state, spec, query.unit)
+                },
+            ),
+        };
+    }
+
+    if spec.writable && !read_only {
+        let unit = spec.unit.map(String::from);
+        methods = match spec.kind {
+            Kind::Axes => methods.post(
+                move |State(state): State<ApplicationState>,
+                      Query(query): Query<scaledqty::PostQuery>,
+                      body: Result<Json<axisqty::AxisQtyRequest>, JsonRejection>| async move {
+                    let request = match axisqty::request_from(body, &query) {
+                        Ok(r) => r,
+                        Err(e) => return e,
+                    };
+                    axisqty::post(state, request, spec, query.verify)
+                        .await
+                        .into_response()
+                },
+            ),
+            _ => methods.post(
+                move |State(state): State<ApplicationState>,
+                      Query(query): Query<scaledqty::PostQuery>,
+                      body: Result<Json<scaledqty::ScaledQtyValue>, JsonRejection>| async move {
+                    let request = match scaledqty::request_from(body, &query) {
+                        Ok(r) => r,
+                        Err(e) => return e,
+                    };
+                    scaledqty::post(
+                        state,
+                        request,
+                        spec.write_uuid(),
+                        spec.length,
+                        spec.scale.unwrap(),
+                        unit.unwrap(),
+                        spec.conversions,
+                        query.verify.then_some(spec.uuid),
+                    )
+                    .await
+                    .into_response()
+                },
+            ),
+        };
+        methods = methods.delete(move |State(state): State<ApplicationState>| {
+            uintqty::delete(state, spec.write_uuid(), spec.length)
+        });
+    }
+
+    methods
+}
+
+/// Create a new router that handles all of the attribute routes, along with
+/// the routes that aren't about any one attribute. Every request to these gets
+/// a single response. See `streaming_router` for the rest.
+///
+/// If `read_only` is set, the routes that change anything aren't there at all,
+/// so they can't be reached.
+///
+/// Unknown paths get a `404` and known paths with the wrong method get a `405`,
+/// both with JSON bodies.
+pub fn router(read_only: bool) -> Router<ApplicationState> {
+    let mut router = Router::new();
+    for spec in ATTRIBUTES.iter() {
+        router = router.route(spec.path, methods(spec, read_only));
+    }
+    router = router
+        .route("/attributes", get(get_attributes))
+        .route("/battery/history", get(history::get_battery_history))
+        .route("/healthz", get(health::get_healthz))
+        .route("/host/status", get(host::get_host_status))
+        .route("/status", get(status::get_status));
+    if !read_only {
+        router = router
+            .route("/command/:name", post(command::post_command))
+            .route(
+                "/light/calibrate",
+                post(calibrate::post_light_calibrate).delete(calibrate::delete_light_calibrate),
+            )
+            .route("/config", post(config::post_config))
+            .route("/reset-config", post(config::post_reset_config))
+            .route("/reconnect", post(health::post_reconnect));
+    }
+    router
+        .fallback(not_found)
+        .method_not_allowed_fallback(method_not_allowed)
+}
+
+/// Create a new router for the routes that expose the ornament's internals.
+/// These aren't served by default.
+pub fn debug_router() -> Router<ApplicationState> {
+    Router::new()
+        .route("/debug/characteristics", get(debug::get_characteristics))
+        .method_not_allowed_fallback(method_not_allowed)
+}
+
+/// Create a new router for the routes that stay open for as long as the client
+/// wants, like WebSockets and server-sent events. These are kept separate so
+/// they can be exempt from request timeouts.
+pub fn streaming_router() -> Router<ApplicationState> {
+    Router::new()
+        .route("/bootcount/stream", get(bootcount::get_bootcount_stream))
+        .route("/ws", get(ws::get_ws))
+        .method_not_allowed_fallback(method_not_allowed)
+}
+
+/// Get the path for the attribute with the given `name`. Attributes are named by
+/// their paths, with `_` in place of `/`. So, `light_threshold` is at
+/// `/light/threshold`.
+pub fn attribute_path(name: &str) -> String {
+    format!("/{}", name.replace('_', "/"))
+}
+
+/// Get the name of the attribute at `path`. This is the inverse of
+/// `attribute_path`.
+pub fn attribute_name(path: &str) -> String {
+    path.trim_start_matches('/').replace('/', "_")
+}
+
+/// Send a request to the `app`'s router without going over the network, so it
+/// does exactly what an HTTP request would. The `body`, if given, is sent as
+/// JSON. Returns the response's status, along with its body parsed as JSON. An
+/// empty body is returned as `null`. Bodies that aren't JSON, like axum's
+/// plain-text rejections, are returned as a string.
+pub async fn dispatch(
+    app: Router,
+    method: Method,
+    path: &str,
+    body: Option<serde_json::Value>,
+) -> anyhow::Result<(StatusCode, serde_json::Value)> {
+    let request = Request::builder().method(method).uri(path);
+    let request = match body {
+        Some(b) => request
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(&b)?))?,
+        None => request.body(Body::empty())?,
     };
+    let response = app.oneshot(request).await?;
 
-    // Finally, read the characteristic and return its value
-    match ble::read_characteristic(&state.peripheral, characteristic).await {
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+    let body = if body.is_empty() {
+        serde_json::Value::Null
+    } else {
+        serde_json::from_slice(&body)
+            .unwrap_or_else(|_| String::from_utf8_lossy(&body).into_owned().into())
+    };
+    Ok((status, body))
+}
+
+/// Utility method for the common task of reading a characteristic and returning
+/// its bytes, given its UUID. Long values come back whole from the one read,
+/// since the OS's BLE stack reassembles them. See `ble::read_characteristic`.
+///
+/// While we're reconnecting, this fails with `AttrError::Transport`, which
+/// tells the client when the next attempt to reconnect starts. Missing
+/// characteristics of `standard` attributes are `AttrError::Unavailable`.
+pub async fn read_characteristic(
+    state: &ApplicationState,
+    uuid: CharUuid,
+) -> Result<Vec<u8>, AttrError> {
+    log::info!(uuid:% = uuid; "Reading characteristic {}", uuid);
+    match state.transport.read(uuid).await {
         Ok(v) => {
             log::debug!("    successfully read characteristic");
             Ok(v)
         }
-        Err(_) => {
-            log::error!("Could not read characteristic with UUID16 {:04x}", uuid16);
-            Err((StatusCode::INTERNAL_SERVER_ERROR, Json(None)))
+        Err(TransportError::NotConnected { retry_after }) => {
+            Err(AttrError::Transport { retry_after })
+        }
+        Err(TransportError::NotFound) if is_standard(uuid) => Err(AttrError::Unavailable { uuid }),
+        Err(TransportError::NotFound) => Err(AttrError::NotFound { uuid }),
+        Err(TransportError::Failed(e)) => {
+            log::debug!("    {:?}", e);
+            Err(AttrError::Unreadable { uuid })
         }
     }
 }
 
+/// Whether `uuid` is read by a `standard` attribute.
+fn is_standard(uuid: CharUuid) -> bool {
+    ATTRIBUTES.iter().any(|a| a.standard && a.uuid == uuid)
+}
+
 /// Utility method for the common task of writing a characteristic's, given its
-/// 16-bit UUID and the bytes to write.
+/// UUID and the bytes to write.
 pub async fn write_characteristic(
     state: &ApplicationState,
-    uuid16: u16,
+    uuid: CharUuid,
     value: &[u8],
-) -> StatusCode {
-    // First, convert the 16-bit UUID to a 128-bit UUID
-    let uuid = ble::uuid_16(uuid16);
-    log::info!("Writing characteristic with UUID16 {:04x}", uuid16);
-
-    // Then, get the characteristic from the service
-    let characteristic = match ble::find_characteristic(&state.service, uuid) {
-        Some(c) => {
-            log::debug!("    successfully found characteristic");
-            c
-        }
-        None => {
-            log::error!("Could not find characteristic with UUID16 {:04x}", uuid16);
-            return StatusCode::NOT_FOUND;
-        }
-    };
-
-    // Finally, write the characteristic and return
-    match ble::write_characteristic(&state.peripheral, characteristic, value).await {
-        Ok(_) => {
+) -> Result<(), AttrError> {
+    log::info!(uuid:% = uuid; "Writing characteristic {}", uuid);
+    match state.transport.write(uuid, value).await {
+        Ok(()) => {
             log::debug!("    successfully wrote characteristic");
-            StatusCode::OK
+            Ok(())
         }
-        Err(_) => {
-            log::error!("Could not write characteristic with UUID16 {:04x}", uuid16);
-            StatusCode::INTERNAL_SERVER_ERROR
+        Err(TransportError::NotConnected { retry_after }) => {
+            Err(AttrError::Transport { retry_after })
+        }
+        Err(TransportError::NotFound) => Err(AttrError::NotFound { uuid }),
+        Err(TransportError::Failed(e)) => {
+            log::debug!("    {:?}", e);
+            Err(AttrError::Unwritable { uuid })
         }
     }
 }
 
-uintqty::get_method!(get_heap, 0x0002, 4, "bytes");
-scaledqty::get_method!(get_battery, 0x0003, 2, 1.00709544518e-4, "volts");
-scaledqty::get_method!(get_light, 0x0004, 4, 1e-3, "lux");
-uintqty::get_method!(get_accelerometer, 0x0005, 3);
-scaledqty::get_method!(get_light_threshold, 0x0006, 4, 1e-1, "lux");
-scaledqty::get_method!(get_accelerometer_threshold, 0x0007, 2, 1e-3, "g");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn read_only_leaves_out_writes() {
+        let ornament = testing::ornament();
+        let mut state = testing::state(ornament.clone());
+        state.read_only = true;
+        let app = testing::app(state);
 
-scaledqty::post_method!(post_light_threshold, 0x0008, 4, 1e-1, "lux");
-scaledqty::post_method!(post_accelerometer_threshold, 0x0009, 2, 1e-3, "g");
+        // A body that would be written if we weren't read-only
+        let body = serde_json::json!({"value": 1.0, "unit": "lux"});
+        let (status, _) =
+            testing::request(&app, Method::POST, "/light/threshold", Some(body.clone())).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        let writable = testing::app(testing::state(testing::ornament()));
+        let (status, _) =
+            testing::request(&writable, Method::POST, "/light/threshold", Some(body)).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = testing::request(&app, Method::DELETE, "/light/threshold", None).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        let (status, _) = testing::request(&app, Method::POST, "/command/reboot", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
 
-uintqty::get_method!(get_bootcount, 0x0010, 1);
+        // Nothing was written, and reads still work
+        let spec = ATTRIBUTES
+            .iter()
+            .find(|a| a.path == "/light/threshold")
+            .unwrap();
+        assert_eq!(ornament.value(spec.uuid).unwrap(), vec![0xff; spec.length]);
+        let (status, _) = testing::request(&app, Method::GET, "/bootcount", None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        ornament.set(BOOTCOUNT_UUID, &[7]);
+        let (status, body) = testing::request(&app, Method::GET, "/bootcount", None).await;
+        assert_eq!((status, body["value"].clone()), (StatusCode::OK, 7.into()));
+    }
+
+    #[tokio::test]
+    async fn attributes_list_uuid16s() {
+        let app = testing::app(testing::state(testing::ornament()));
+        let (status, body) = testing::request(&app, Method::GET, "/attributes", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let threshold = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|a| a["path"] == "/light/threshold")
+            .unwrap();
+        assert_eq!(threshold["uuid"], serde_json::json!({"uuid16": 6}));
+        assert_eq!(threshold["write_uuid"], serde_json::json!({"uuid16": 8}));
+    }
+
+    #[tokio::test]
+    async fn plain_text_bodies_are_strings() {
+        let app = testing::app(testing::state(testing::ornament()));
+        let body = serde_json::json!({"value": "five", "unit": "lux"});
+        let (status, body) =
+            testing::request(&app, Method::POST, "/light/threshold", Some(body)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(
+            body.as_str().is_some_and(|b| b.contains("value")),
+            "{}",
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn long_values_come_back_whole() {
+        // Longer than one read response at the default MTU of 23
+        let uuid = CharUuid::short(0x0040);
+        let value: Vec<u8> = (0..30).collect();
+        let ornament = testing::ornament();
+        ornament.set(uuid, &value);
+        let state = testing::state(ornament);
+
+        assert_eq!(read_characteristic(&state, uuid).await.unwrap(), value);
+    }
+}
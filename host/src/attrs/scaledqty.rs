@@ -3,13 +3,17 @@
 //!
 //! See crate::attrs::uintqty
 
+use axum::extract::rejection::JsonRejection;
 use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
 use axum::Json;
 use serde::{Deserialize, Serialize};
 
+use crate::attrs;
 use crate::attrs::uintqty;
 use crate::attrs::uintqty::UIntQtyValue;
-use crate::attrs::ApplicationState;
+use crate::attrs::{ApplicationState, AttrError};
+use crate::ble::CharUuid;
 
 #[derive(Deserialize, Serialize)]
 pub struct ScaledQtyValue {
@@ -17,82 +21,437 @@ pub struct ScaledQtyValue {
     pub unit: String,
 }
 
-/// Macro to generate a `GET` method.
-macro_rules! get_method {
-    ($name:ident, $uuid16:literal, $length:literal, $scale:literal, $unit:literal) => {
-        async fn $name(
-            axum::extract::State(state): axum::extract::State<$crate::attrs::ApplicationState>,
-        ) -> (
-            axum::http::StatusCode,
-            axum::extract::Json<Option<$crate::attrs::scaledqty::ScaledQtyValue>>,
-        ) {
-            static_assertions::const_assert!($length != 0);
-            static_assertions::const_assert!($length <= 8);
-            $crate::attrs::scaledqty::get(state, $uuid16, $length, $scale, String::from($unit))
-                .await
-        }
-    };
+/// Units other than an attribute's own unit that its value can be expressed in.
+/// Each entry is the name of the unit, along with how many of it make up one of
+/// the attribute's unit. `GET` methods convert to these on request, and `POST`
+/// methods accept them.
+pub type Conversions = &'static [(&'static str, f64)];
+
+/// Conversions for attributes measured in g.
+pub static G_CONVERSIONS: Conversions = &[("m/s²", 9.80665)];
+
+/// Query parameters accepted by the `GET` methods. If `unit` is not given, the
+/// value is returned in the attribute's own unit.
+#[derive(Deserialize)]
+pub struct UnitQuery {
+    pub unit: Option<String>,
+}
+
+/// Convert a `raw` value read from a characteristic to the quantity it
+/// represents. Each LSB is worth `scale`.
+pub fn from_raw(raw: u64, scale: f64) -> f64 {
+    raw as f64 * scale
+}
+
+/// A calibrated map from raw values to quantities, `raw * scale + offset`. Most
+/// attributes only have a `scale`, which is the same as an offset of zero.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Affine {
+    pub scale: f64,
+    pub offset: f64,
+}
+
+impl Affine {
+    /// The map with no offset, which is what `from_raw` does.
+    pub fn linear(scale: f64) -> Self {
+        Affine { scale, offset: 0.0 }
+    }
+
+    /// Convert a `raw` value to the quantity it represents. Unlike `from_raw`,
+    /// this takes a float, so averages of raw values can be converted too.
+    pub fn apply(self, raw: f64) -> f64 {
+        raw * self.scale + self.offset
+    }
+}
+
+/// Convert a `value` to the raw integer to write to a characteristic of the
+/// given `length`, where each LSB is worth `scale`. This is the inverse of
+/// `from_raw`, up to rounding. We round to the nearest LSB, with ties going
+/// away from zero.
+///
+/// Casting to an integer saturates, so we have to check that the value fits in
+/// the characteristic ourselves, before casting. On failure, returns why.
+pub fn to_raw(value: f64, scale: f64, length: usize) -> Result<u64, String> {
+    let raw = (value / scale).round();
+    if !raw.is_finite() {
+        return Err(String::from("Value is not finite"));
+    }
+    let max = ((1u128 << (8 * length)) - 1) as f64;
+    if raw < 0.0 || raw > max {
+        return Err(format!("Value does not fit in {} bytes", length));
+    }
+    Ok(raw as u64)
+}
+
+/// Find how many of `requested` make up one of `unit`, given the other units
+/// the attribute can be expressed in. Returns `None` if the unit is not known.
+pub fn conversion_factor(unit: &str, conversions: Conversions, requested: &str) -> Option<f64> {
+    if requested == unit {
+        return Some(1.0);
+    }
+    conversions
+        .iter()
+        .find(|(name, _)| *name == requested)
+        .map(|(_, factor)| *factor)
 }
-pub(crate) use get_method;
 
 /// Generic method for `GET` requests. The only difference between this and the
 /// `uintqty::get` method is that this takes a `scale` parameter. This is the
 /// amount that `1` is multiplied by to get the actual value. Also, it returns
-/// a different type.
+/// a different type. The value is converted to the `requested` unit if one is
+/// given, which must be either `unit` or one of the `conversions`.
+#[allow(clippy::too_many_arguments)]
 pub async fn get(
     state: ApplicationState,
-    uuid16: u16,
+    uuid: CharUuid,
     length: usize,
+    unset_marker: bool,
     scale: f64,
     unit: String,
-) -> (StatusCode, Json<Option<ScaledQtyValue>>) {
+    conversions: Conversions,
+    requested: Option<String>,
+) -> Result<Json<ScaledQtyValue>, AttrError> {
+    // Figure out what unit to return before doing any I/O
+    let requested = requested.unwrap_or_else(|| unit.clone());
+    let factor = conversion_factor(&unit, conversions, &requested).ok_or_else(|| {
+        AttrError::UnitMismatch {
+            expected: Some(unit.clone()),
+            got: Some(requested.clone()),
+        }
+    })?;
+
     // Call into the `uintqty` module to read the characteristic
-    let (resp, val) = uintqty::get(state, uuid16, length, Some(unit.clone())).await;
+    let val = uintqty::read(&state, uuid, length, unset_marker).await?;
 
-    // If the value is `None`, just immediately return
-    let val = match val {
-        Json(None) => return (resp, Json(None)),
-        Json(Some(v)) => v,
-    };
+    // Scale the value and return it. Note that units are mandatory.
+    Ok(Json(ScaledQtyValue {
+        value: from_raw(val, scale) * factor,
+        unit: requested,
+    }))
+}
 
-    // Otherwise, scale the value and return it. Note that units are mandatory.
-    let scaled = ScaledQtyValue {
-        value: val.value as f64 * scale,
-        unit,
-    };
-    (resp, Json(Some(scaled)))
+/// Query parameters accepted by the `POST` methods. If `verify` is set, the
+/// value is read back after it is written. See `uintqty::verify`.
+///
+/// The `value` and `unit` can be given here instead of as a JSON body. They're
+/// only used if there is no body.
+#[derive(Deserialize)]
+pub struct PostQuery {
+    #[serde(default)]
+    pub verify: bool,
+    pub value: Option<f64>,
+    pub unit: Option<String>,
+}
+
+/// Get the request for a `POST` method, either from its JSON `body` or from its
+/// `query` parameters if it doesn't have one. On failure, returns the response
+/// to send. Errors with the body are returned as-is. Requests in the query
+/// parameters are always `ScaledQtyValue`s, so other request types have to be
+/// convertible from one.
+#[allow(clippy::result_large_err)]
+pub fn request_from<T: From<ScaledQtyValue>>(
+    body: Result<Json<T>, JsonRejection>,
+    query: &PostQuery,
+) -> Result<T, Response> {
+    match body {
+        Ok(Json(request)) => Ok(request),
+        Err(JsonRejection::MissingJsonContentType(_)) => match (query.value, &query.unit) {
+            (Some(value), Some(unit)) => Ok(T::from(ScaledQtyValue {
+                value,
+                unit: unit.clone(),
+            })),
+            _ => Err(attrs::bad_request(String::from(
+                "Expected a JSON body, or both `value` and `unit` as query parameters",
+            ))),
+        },
+        Err(e) => Err(e.into_response()),
+    }
 }
 
-/// Macro to generate a `POST` method for a characteristic.
-macro_rules! post_method {
-    ($name:ident, $uuid16:literal, $length:literal, $scale:literal, $unit:literal) => {
-        async fn $name(
-            axum::extract::State(state): axum::extract::State<$crate::attrs::ApplicationState>,
-            axum::extract::Json(request): axum::extract::Json<$crate::attrs::scaledqty::ScaledQtyValue>,
-        ) -> axum::http::StatusCode {
-            static_assertions::const_assert!($length != 0);
-            static_assertions::const_assert!($length <= 8);
-            $crate::attrs::scaledqty::post(state, request, $uuid16, $length, $scale, String::from($unit)).await
+/// The response body for a verified `POST`. This has the value that was
+/// actually written after rounding, and the value that was read back, if any.
+/// Both are in the characteristic's unit.
+#[derive(Serialize)]
+pub struct Verification<T = ScaledQtyValue> {
+    pub written: T,
+    pub read_back: Option<T>,
+}
+
+/// Convert a `request` to the raw integer to write to a characteristic of the
+/// given `length`. The request may be in either `unit` or one of the
+/// `conversions`, and it's converted to `unit` first. The result still has to
+/// pass `uintqty::to_bytes`.
+pub fn encode(
+    request: &ScaledQtyValue,
+    length: usize,
+    scale: f64,
+    unit: &str,
+    conversions: Conversions,
+) -> Result<u64, AttrError> {
+    let factor = conversion_factor(unit, conversions, &request.unit).ok_or_else(|| {
+        AttrError::UnitMismatch {
+            expected: Some(String::from(unit)),
+            got: Some(request.unit.clone()),
         }
-    };
+    })?;
+    to_raw(request.value / factor, scale, length)
+        .map_err(|e| AttrError::OutOfRange(format!("{}: {} {}", e, request.value, request.unit)))
 }
-pub(crate) use post_method;
 
-/// Generic method for `POST` requests.
+/// Generic method for `POST` requests. Configuration characteristics come in
+/// pairs, so this writes to `uuid` and reads back from the one in `verify`.
+/// The request is converted with `encode` before being written.
+///
+/// If `verify` is given, the value is read back from that characteristic after
+/// it is written. We return `500` if it doesn't match. Either way, the body is
+/// a `Verification`. Otherwise, the body is empty. Failures before then are an
+/// `AttrError`.
+#[allow(clippy::too_many_arguments)]
 pub async fn post(
     state: ApplicationState,
     request: ScaledQtyValue,
-    uuid16: u16,
+    uuid: CharUuid,
     length: usize,
     scale: f64,
     unit: String,
-) -> StatusCode {
-    // Scale the value back to an integer
-    let scaled = (request.value / scale).round() as u64;
+    conversions: Conversions,
+    verify: Option<CharUuid>,
+) -> Result<Response, AttrError> {
+    // Scale the value back to an integer in the characteristic's unit
+    let scaled = encode(&request, length, scale, &unit, conversions)?;
     let scaled_request = UIntQtyValue {
         value: scaled,
         unit: Some(unit.clone()),
     };
 
-    uintqty::post(state, scaled_request, uuid16, length, Some(unit)).await
+    uintqty::post(
+        state.clone(),
+        scaled_request,
+        uuid,
+        length,
+        Some(unit.clone()),
+    )
+    .await?;
+    // Only read back if the write itself succeeded
+    let Some(readback_uuid) = verify else {
+        return Ok(StatusCode::OK.into_response());
+    };
+
+    let to_scaled = |v: u64| ScaledQtyValue {
+        value: from_raw(v, scale),
+        unit: unit.clone(),
+    };
+    let (resp, read_back) = match uintqty::verify(&state, readback_uuid, length, scaled).await {
+        Ok(v) => (StatusCode::OK, Some(v)),
+        Err(v) => (StatusCode::INTERNAL_SERVER_ERROR, v),
+    };
+    let body = Verification {
+        written: to_scaled(scaled),
+        read_back: read_back.map(to_scaled),
+    };
+    Ok((resp, Json(body)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use super::*;
+    use crate::attrs::testing;
+    use crate::attrs::{AttributeSpec, Kind, ATTRIBUTES};
+
+    /// Every attribute that has a scale, along with its largest raw value that
+    /// isn't the unset marker.
+    fn scaled() -> impl Iterator<Item = (&'static AttributeSpec, u64)> {
+        ATTRIBUTES.iter().filter(|s| s.scale.is_some()).map(|s| {
+            let max = (1u64 << (8 * s.length as u32 - 1) << 1).wrapping_sub(1);
+            (s, if s.unset_marker { max - 1 } else { max })
+        })
+    }
+
+    #[test]
+    fn raw_round_trips_for_every_scale() {
+        for (spec, max) in scaled() {
+            let scale = spec.scale.unwrap();
+            for raw in [0, 1, 2, max / 3, max / 2, max] {
+                let value = from_raw(raw, scale);
+                assert_eq!(to_raw(value, scale, spec.length), Ok(raw), "{}", spec.path);
+            }
+        }
+    }
+
+    #[test]
+    fn rounds_to_nearest_lsb_for_every_scale() {
+        for (spec, max) in scaled() {
+            let scale = spec.scale.unwrap();
+            for raw in [1, max / 2, max - 1] {
+                let below = (raw as f64 - 0.4) * scale;
+                let above = (raw as f64 + 0.4) * scale;
+                assert_eq!(to_raw(below, scale, spec.length), Ok(raw), "{}", spec.path);
+                assert_eq!(to_raw(above, scale, spec.length), Ok(raw), "{}", spec.path);
+            }
+        }
+    }
+
+    #[test]
+    fn ties_round_away_from_zero() {
+        // Scales that are powers of two make the halfway points exact
+        assert_eq!(to_raw(1.25, 0.5, 2), Ok(3));
+        assert_eq!(to_raw(0.75, 0.5, 2), Ok(2));
+        assert_eq!(to_raw(0.125, 0.25, 2), Ok(1));
+        // And the same tie always goes the same way
+        for _ in 0..3 {
+            assert_eq!(to_raw(2.5, 1.0, 1), Ok(3));
+        }
+    }
+
+    #[test]
+    fn quantization_error_is_at_most_half_an_lsb() {
+        for (spec, max) in scaled() {
+            let scale = spec.scale.unwrap();
+            let top = from_raw(max, scale);
+            for i in 0..=100 {
+                let value = top * i as f64 / 100.0;
+                let raw = to_raw(value, scale, spec.length).unwrap();
+                let error = (from_raw(raw, scale) - value).abs();
+                // Large values can't be represented exactly either
+                let slack = value * 4.0 * f64::EPSILON;
+                assert!(error <= scale / 2.0 + slack, "{} at {}", spec.path, value);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn post_then_get_is_within_quantization_error() {
+        let specs = ATTRIBUTES
+            .iter()
+            .filter(|s| s.writable && s.kind == Kind::Scaled);
+        for spec in specs {
+            let app = testing::app(testing::state(testing::ornament()));
+            let scale = spec.scale.unwrap();
+            let value = 1234.5678 * scale;
+            let body = json!({ "value": value, "unit": spec.unit.unwrap() });
+
+            let (status, _) = testing::request(&app, Method::POST, spec.path, Some(body)).await;
+            assert_eq!(status, StatusCode::OK, "{}", spec.path);
+            let (status, body) = testing::request(&app, Method::GET, spec.path, None).await;
+            assert_eq!(status, StatusCode::OK, "{}", spec.path);
+            let read = body["value"].as_f64().unwrap();
+            assert!((read - value).abs() <= scale / 2.0, "{}", spec.path);
+        }
+    }
+
+    #[test]
+    fn g_and_metres_per_second_squared_encode_the_same() {
+        let spec = ATTRIBUTES
+            .iter()
+            .find(|s| s.path == "/accelerometer/threshold")
+            .unwrap();
+        let bytes = |value: f64, unit: &str| {
+            let request = ScaledQtyValue {
+                value,
+                unit: String::from(unit),
+            };
+            let raw = encode(
+                &request,
+                spec.length,
+                spec.scale.unwrap(),
+                spec.unit.unwrap(),
+                spec.conversions,
+            );
+            raw.and_then(|r| uintqty::to_bytes(r, spec.length))
+        };
+        assert_eq!(bytes(1.0, "g").unwrap(), bytes(9.80665, "m/s²").unwrap());
+        assert_eq!(bytes(0.25, "g").unwrap(), bytes(2.4516625, "m/s²").unwrap());
+        assert!(matches!(
+            bytes(1.0, "ft/s²"),
+            Err(AttrError::UnitMismatch { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn posting_either_unit_writes_the_same_bytes() {
+        let spec = ATTRIBUTES
+            .iter()
+            .find(|s| s.path == "/accelerometer/threshold")
+            .unwrap();
+        let mut written = Vec::new();
+        for body in [
+            json!({ "value": 1.0, "unit": "g" }),
+            json!({ "value": 9.80665, "unit": "m/s²" }),
+        ] {
+            let ornament = testing::ornament();
+            let app = testing::app(testing::state(ornament.clone()));
+            let (status, _) = testing::request(&app, Method::POST, spec.path, Some(body)).await;
+            assert_eq!(status, StatusCode::OK);
+            written.push(ornament.value(spec.uuid).unwrap());
+        }
+        assert_eq!(written[0], written[1]);
+
+        let app = testing::app(testing::state(testing::ornament()));
+        let body = json!({ "value": 1.0, "unit": "ft/s²" });
+        let (status, _) = testing::request(&app, Method::POST, spec.path, Some(body)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn all_ones_is_the_maximum_without_the_marker() {
+        // A 2-byte sensor in milli-g that rails high when it saturates
+        let uuid = CharUuid::short(0x0040);
+        let ornament = testing::ornament();
+        ornament.set(uuid, &[0xff, 0xff]);
+        let state = testing::state(ornament);
+        let get = |unset_marker| {
+            let unit = String::from("g");
+            get(state.clone(), uuid, 2, unset_marker, 1e-3, unit, &[], None)
+        };
+
+        let reading = get(false).await.unwrap();
+        assert_eq!(reading.value, 65.535);
+        assert_eq!(reading.unit, "g");
+        assert!(matches!(get(true).await, Err(AttrError::Unset { .. })));
+    }
+
+    #[test]
+    fn rejects_values_that_are_not_finite() {
+        for value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(to_raw(value, 1e-3, 2).is_err(), "{}", value);
+            let request = ScaledQtyValue {
+                value,
+                unit: String::from("g"),
+            };
+            let e = encode(&request, 2, 1e-3, "g", &[]).unwrap_err();
+            assert_eq!(e.status(), StatusCode::BAD_REQUEST, "{}", value);
+        }
+        // Finite values can still overflow once they're scaled
+        assert!(to_raw(f64::MAX, 1e-3, 2).is_err());
+    }
+
+    #[test]
+    fn rejects_one_above_the_maximum() {
+        assert_eq!(to_raw(65535.0, 1.0, 2), Ok(0xffff));
+        assert!(to_raw(65536.0, 1.0, 2).is_err());
+        assert_eq!(to_raw(65.535, 1e-3, 2), Ok(0xffff));
+        assert!(to_raw(65.536, 1e-3, 2).is_err());
+        assert!(to_raw(-1.0, 1.0, 2).is_err());
+    }
+
+    #[tokio::test]
+    async fn out_of_range_posts_are_400_and_not_written() {
+        let spec = ATTRIBUTES
+            .iter()
+            .find(|s| s.writable && s.length == 2)
+            .unwrap();
+        let scale = spec.scale.unwrap();
+        let ornament = testing::ornament();
+        let app = testing::app(testing::state(ornament.clone()));
+        for value in [65536.0 * scale, 1e308, -scale] {
+            let body = json!({ "value": value, "unit": spec.unit.unwrap() });
+            let (status, body) = testing::request(&app, Method::POST, spec.path, Some(body)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", value);
+            assert!(body["error"].is_string());
+            assert_eq!(ornament.value(spec.uuid).unwrap(), vec![0xff; 2]);
+        }
+    }
 }
@@ -0,0 +1,92 @@
+//! Scaled attributes that are too noisy to use reading-by-reading, so we also
+//! report a rolling average of them. Every attribute has its own window, but
+//! they're all the same size. Every `GET` adds to it.
+//!
+//! See crate::attrs::scaledqty
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use axum::Json;
+use serde::Serialize;
+
+use crate::attrs::scaledqty::{self, Affine};
+use crate::attrs::uintqty;
+use crate::attrs::{ApplicationState, AttrError, AttributeSpec};
+
+/// The response for `GET` requests. The `value` is the average over the window,
+/// and `raw` is the reading we just took. Both are in `unit`. The `lsb` is that
+/// same reading before it was scaled, which is what calibration points use.
+#[derive(Serialize)]
+pub struct SmoothedQtyValue {
+    pub value: f64,
+    pub raw: f64,
+    pub lsb: u64,
+    pub unit: String,
+}
+
+/// The last few raw readings of each smoothed attribute, keyed by path. Each
+/// keeps at most `window` of them.
+#[derive(Clone)]
+pub struct RollingAverage {
+    readings: Arc<Mutex<HashMap<&'static str, VecDeque<u64>>>>,
+    window: usize,
+}
+
+impl RollingAverage {
+    /// Average over the last `window` readings. A window of `1` doesn't smooth
+    /// at all, and neither does `0`.
+    pub fn new(window: usize) -> Self {
+        RollingAverage {
+            readings: Default::default(),
+            window: window.max(1),
+        }
+    }
+
+    /// Add the `raw` reading for the attribute at `path`, and return the
+    /// average of the readings in its window, including this one.
+    pub fn push(&self, path: &'static str, raw: u64) -> f64 {
+        let mut readings = self.readings.lock().unwrap();
+        let window = readings.entry(path).or_default();
+        if window.len() == self.window {
+            window.pop_front();
+        }
+        window.push_back(raw);
+        window.iter().map(|r| *r as f64).sum::<f64>() / window.len() as f64
+    }
+}
+
+/// Generic method for `GET` requests. This is the same as `scaledqty::get`,
+/// except that it also averages the reading into the attribute's window, and
+/// uses the attribute's calibration if it has one. See `crate::attrs::calibrate`.
+pub async fn get(
+    state: ApplicationState,
+    spec: &'static AttributeSpec,
+    requested: Option<String>,
+) -> Result<Json<SmoothedQtyValue>, AttrError> {
+    let unit = spec.unit.unwrap();
+    let affine = state
+        .calibrations
+        .get(spec.path)
+        .unwrap_or_else(|| Affine::linear(spec.scale.unwrap()));
+
+    // Figure out what unit to return before doing any I/O
+    let requested = requested.unwrap_or_else(|| String::from(unit));
+    let factor =
+        scaledqty::conversion_factor(unit, spec.conversions, &requested).ok_or_else(|| {
+            AttrError::UnitMismatch {
+                expected: Some(String::from(unit)),
+                got: Some(requested.clone()),
+            }
+        })?;
+
+    let raw = uintqty::read(&state, spec.uuid, spec.length, spec.unset_marker).await?;
+    let average = state.averages.push(spec.path, raw);
+
+    Ok(Json(SmoothedQtyValue {
+        value: affine.apply(average) * factor,
+        raw: affine.apply(raw as f64) * factor,
+        lsb: raw,
+        unit: requested,
+    }))
+}
@@ -0,0 +1,124 @@
+//! A cache of the last values read for the ornament's sensors, along with the
+//! `/status` endpoint that serves it. Reading the cache never touches BLE, so
+//! it's cheap to poll. The cache is only as fresh as whatever refreshes it.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::extract::State;
+use axum::http::Method;
+use axum::{Json, Router};
+use serde::Serialize;
+
+use crate::attrs;
+use crate::attrs::ApplicationState;
+use crate::transport::ConnectionState;
+
+/// One cached reading. The `value` is exactly what the corresponding `GET`
+/// method returned, and the `timestamp` is when it was read, in seconds since
+/// the UNIX epoch.
+#[derive(Clone, Serialize)]
+pub struct CachedReading {
+    pub value: serde_json::Value,
+    pub timestamp: u64,
+}
+
+/// Shared storage for cached readings, keyed by the attribute's name.
+#[derive(Clone, Default)]
+pub struct SensorCache(Arc<RwLock<BTreeMap<&'static str, CachedReading>>>);
+
+impl SensorCache {
+    /// Record a new reading for the attribute `name`.
+    pub fn insert<T: Serialize>(&self, name: &'static str, value: &T) {
+        let value = match serde_json::to_value(value) {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("Could not serialize reading for {}: {:?}", name, e);
+                return;
+            }
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.0
+            .write()
+            .unwrap()
+            .insert(name, CachedReading { value, timestamp });
+    }
+
+    /// Take a copy of all the readings.
+    pub fn snapshot(&self) -> BTreeMap<&'static str, CachedReading> {
+        self.0.read().unwrap().clone()
+    }
+}
+
+/// The common sensors that `refresh_sensors` reads, by name.
+static SENSORS: [&str; 4] = ["heap", "battery", "light", "accelerometer"];
+
+/// Read all of the common sensors through the `app` and update the `state`'s
+/// cache with their values. The `app` is the router for the `state`, built
+/// once by the caller rather than every time. Read failures are logged and
+/// leave the old value in place. They are not returned, since a failing sensor
+/// doesn't mean we've lost the ornament.
+pub async fn refresh_sensors(state: &ApplicationState, app: &Router) {
+    for name in SENSORS {
+        let path = attrs::attribute_path(name);
+        match attrs::dispatch(app.clone(), Method::GET, &path, None).await {
+            Ok((status, value)) if status.is_success() => state.cache.insert(name, &value),
+            Ok((status, _)) => {
+                log::warn!("Could not refresh cached reading for {}: {}", name, status)
+            }
+            Err(e) => log::warn!("Could not refresh cached reading for {}: {:?}", name, e),
+        }
+    }
+}
+
+/// The response for `GET /status`.
+#[derive(Serialize)]
+pub struct Status {
+    pub connected: bool,
+    pub sensors: BTreeMap<&'static str, CachedReading>,
+}
+
+/// Whether we're connected to the ornament, and the cached sensor readings.
+/// This doesn't do any BLE reads.
+pub async fn current(state: &ApplicationState) -> Status {
+    Status {
+        connected: state.transport.state().await == ConnectionState::Connected,
+        sensors: state.cache.snapshot(),
+    }
+}
+
+/// Report the `current` status.
+pub async fn get_status(State(state): State<ApplicationState>) -> Json<Status> {
+    Json(current(&state).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attrs::testing;
+    use crate::ble::CharUuid;
+
+    #[tokio::test]
+    async fn refreshing_fills_the_cache() {
+        let ornament = testing::ornament();
+        let state = testing::state(ornament.clone());
+        let app = testing::app(state.clone());
+
+        // Unset sensors aren't cached
+        refresh_sensors(&state, &app).await;
+        assert!(state.cache.snapshot().is_empty());
+
+        ornament.set(CharUuid::short(0x0002), &[0, 0, 1, 0]);
+        refresh_sensors(&state, &app).await;
+        let sensors = state.cache.snapshot();
+        assert_eq!(sensors.keys().copied().collect::<Vec<_>>(), ["heap"]);
+
+        let status = current(&state).await;
+        assert!(status.connected);
+        assert_eq!(status.sensors.len(), 1);
+    }
+}
@@ -0,0 +1,66 @@
+//! Helpers for testing the routes without an ornament. Everything goes through
+//! a `MockTransport`, and requests go through `dispatch` like `--once` does.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::http::{Method, StatusCode};
+use axum::Router;
+
+use crate::attrs;
+use crate::attrs::{ApplicationState, BatteryHistory, RollingAverage, ATTRIBUTES};
+use crate::transport::MockTransport;
+
+/// A mock ornament with every attribute's characteristic, all unset. Writes to
+/// configuration characteristics show up on the ones they're read from, like on
+/// the real ornament.
+pub fn ornament() -> Arc<MockTransport> {
+    let mock = MockTransport::default();
+    for spec in ATTRIBUTES.iter() {
+        mock.set(spec.uuid, &vec![0xff; spec.length]);
+        if let Some(w) = spec.write_uuid {
+            mock.alias(w, spec.uuid);
+        }
+    }
+    mock.set(attrs::command::COMMAND_UUID, &[0]);
+    Arc::new(mock)
+}
+
+/// State for handling requests with the `transport`, with everything else at
+/// its defaults.
+pub fn state(transport: Arc<MockTransport>) -> ApplicationState {
+    ApplicationState {
+        transport,
+        cache: Default::default(),
+        bootcount: Default::default(),
+        battery_history: BatteryHistory::new(4),
+        averages: RollingAverage::new(1),
+        calibrations: Default::default(),
+        metrics: Default::default(),
+        raw_commands: false,
+        read_only: false,
+        lenient_length: false,
+        axis_thresholds: false,
+        request_timeout: Duration::from_secs(30),
+        shutdown: Default::default(),
+    }
+}
+
+/// The app `main` would build for the `state`, without the middleware.
+pub fn app(state: ApplicationState) -> Router {
+    attrs::router(state.read_only)
+        .merge(attrs::debug_router())
+        .with_state(state)
+}
+
+/// Send a request to the `app`, and get back its status and JSON body.
+pub async fn request(
+    app: &Router,
+    method: Method,
+    path: &str,
+    body: Option<serde_json::Value>,
+) -> (StatusCode, serde_json::Value) {
+    attrs::dispatch(app.clone(), method, path, body)
+        .await
+        .unwrap()
+}
@@ -1,12 +1,14 @@
 //! Attributes that represent unsigned integer quantities. These are an unsigned
 //! integers that optionally have a unit.
 
-use axum::http::StatusCode;
+use std::time::Duration;
+
 use axum::Json;
 use serde::{Deserialize, Serialize};
 
 use crate::attrs;
-use crate::attrs::ApplicationState;
+use crate::attrs::{ApplicationState, AttrError};
+use crate::ble::CharUuid;
 
 /// An unsigned integer quantity with an optional unit. This is the type that is
 /// returned by the `GET` methods and ingested by `POST` methods.
@@ -16,125 +18,266 @@ pub struct UIntQtyValue {
     pub unit: Option<String>,
 }
 
-/// Macro to generate a `GET` method for a characteristic.
-macro_rules! get_method {
-    ($name:ident, $uuid16:literal, $length:literal, $unit:literal) => {
-        async fn $name(
-            axum::extract::State(state): axum::extract::State<$crate::attrs::ApplicationState>,
-        ) -> (
-            axum::http::StatusCode,
-            axum::extract::Json<Option<$crate::attrs::uintqty::UIntQtyValue>>,
-        ) {
-            static_assertions::const_assert!($length != 0);
-            static_assertions::const_assert!($length <= 8);
-            $crate::attrs::uintqty::get(state, $uuid16, $length, Some(String::from($unit))).await
-        }
-    };
-    ($name:ident, $uuid16:literal, $length:literal) => {
-        async fn $name(
-            axum::extract::State(state): axum::extract::State<$crate::attrs::ApplicationState>,
-        ) -> (
-            axum::http::StatusCode,
-            axum::extract::Json<Option<$crate::attrs::uintqty::UIntQtyValue>>,
-        ) {
-            static_assertions::const_assert!($length != 0);
-            static_assertions::const_assert!($length <= 8);
-            $crate::attrs::uintqty::get(state, $uuid16, $length, None).await
-        }
-    };
-}
-pub(crate) use get_method;
-
-/// Generic method for `GET` requests. Other `get_*` methods will call this one.
+/// Generic method for `GET` requests. Unsigned integer attributes use this.
 /// It takes the `uuid` of the characteristic to read, the `length` of the
-/// attribute in bytes, and an optional `unit` to attach to the value.
+/// attribute in bytes, whether it has an `unset_marker` as in `read`, and an
+/// optional `unit` to attach to the value.
 pub async fn get(
     state: ApplicationState,
-    uuid16: u16,
+    uuid: CharUuid,
     length: usize,
+    unset_marker: bool,
     unit: Option<String>,
-) -> (StatusCode, Json<Option<UIntQtyValue>>) {
+) -> Result<Json<UIntQtyValue>, AttrError> {
+    let num = read(&state, uuid, length, unset_marker).await?;
+    Ok(Json(UIntQtyValue { value: num, unit }))
+}
+
+/// Read the characteristic with the given `uuid` and decode it as a number,
+/// given its `length` in bytes. If the characteristic has an `unset_marker` and
+/// the value isn't set yet, that's `AttrError::Unset`. Otherwise, all 0xff bytes
+/// is just a big number.
+///
+/// If the ornament returns more bytes than we expect, and the application was
+/// told to be lenient about it, we keep the first `length` bytes and ignore the
+/// rest.
+pub async fn read(
+    state: &ApplicationState,
+    uuid: CharUuid,
+    length: usize,
+    unset_marker: bool,
+) -> Result<u64, AttrError> {
     // Read the characteristic
-    let bytes = match attrs::read_characteristic::<UIntQtyValue>(&state, uuid16).await {
-        Ok(v) => v,
-        Err(e) => return e,
-    };
+    let mut bytes = attrs::read_characteristic(state, uuid).await?;
     // Check that the value is the correct length
+    if bytes.len() > length && state.lenient_length {
+        log::warn!(
+            uuid:% = uuid;
+            "Characteristic {}: expected {} bytes, but got {}. Truncating.",
+            uuid,
+            length,
+            bytes.len()
+        );
+        bytes.truncate(length);
+    }
     if bytes.len() != length {
-        log::error!("Expected {} bytes, but got {}", length, bytes.len());
-        return (StatusCode::INTERNAL_SERVER_ERROR, Json(None));
+        return Err(AttrError::BadLength {
+            uuid,
+            expected: length,
+            actual: bytes.len(),
+        });
     }
 
     // Special case: if all the bytes are 0xff, then the value has not yet been
-    // set by the ornament.
-    if bytes.iter().all(|b| *b == 0xff) {
-        return (StatusCode::SERVICE_UNAVAILABLE, Json(None));
+    // set by the ornament. Tell the client to try again in a bit.
+    if unset_marker && bytes.iter().all(|b| *b == 0xff) {
+        return Err(AttrError::Unset { uuid });
     }
 
-    // Convert the value to a number. Note that the returned bytes are
-    // big-endian.
-    let num = {
-        let mut num = 0u64;
-        for byte in bytes.iter() {
-            num <<= 8;
-            num |= Into::<u64>::into(*byte);
-        }
-        num
-    };
-    log::debug!("Characteristic {:04x} - {}", uuid16, num);
-
-    (
-        StatusCode::OK,
-        Json(Some(UIntQtyValue { value: num, unit })),
-    )
+    let num = from_bytes(&bytes);
+    log::debug!(uuid:% = uuid; "Characteristic {} - {}", uuid, num);
+    Ok(num)
 }
 
-/// Generic method for `POST` requests. Other `post_*` methods will call this
-/// one. The units of the request must match the `unit` of the characteristic.
+/// Convert the `bytes` of a characteristic to a number. Note that the bytes are
+/// big-endian.
+pub fn from_bytes(bytes: &[u8]) -> u64 {
+    let mut num = 0u64;
+    for byte in bytes.iter() {
+        num <<= 8;
+        num |= Into::<u64>::into(*byte);
+    }
+    num
+}
+
+/// Generic method for `POST` requests. The units of the request must match the
+/// `unit` of the characteristic.
 ///
 /// This method happens to never be directly used in the current implementation.
-/// It is used by `scaledqty::post` instead. That's why we don't have a
-/// `post_method` macro for this method.
+/// It is used by `scaledqty::post` instead. That's why unsigned integer
+/// attributes can't be writable.
 pub async fn post(
     state: ApplicationState,
     request: UIntQtyValue,
-    uuid16: u16,
+    uuid: CharUuid,
     length: usize,
     unit: Option<String>,
-) -> StatusCode {
+) -> Result<(), AttrError> {
     // Check that the unit matches the characteristic
     if unit != request.unit {
-        log::error!(
-            "Expected unit {:?}, but got {:?}",
-            unit.as_deref(),
-            request.unit
-        );
-        return StatusCode::BAD_REQUEST;
+        return Err(AttrError::UnitMismatch {
+            expected: unit,
+            got: request.unit,
+        });
     }
 
-    // Convert the value to bytes. Note that the bytes are big-endian.
-    let (bytes, num) = {
-        let mut bytes = Vec::with_capacity(length);
-        let mut num = request.value;
-        for _ in 0..length {
-            bytes.push((num & 0xff) as u8);
-            num >>= 8;
-        }
-        bytes.reverse();
-        (bytes, num)
-    };
+    // Write the characteristic
+    let bytes = to_bytes(request.value, length)?;
+    attrs::write_characteristic(&state, uuid, &bytes).await
+}
+
+/// Convert a `value` to the bytes to write to a characteristic of the given
+/// `length`. This is the inverse of `from_bytes`, so the bytes are big-endian.
+/// Fails if the value doesn't fit, or if it's the invalid marker.
+pub fn to_bytes(value: u64, length: usize) -> Result<Vec<u8>, AttrError> {
+    let mut bytes = Vec::with_capacity(length);
+    let mut num = value;
+    for _ in 0..length {
+        bytes.push((num & 0xff) as u8);
+        num >>= 8;
+    }
+    bytes.reverse();
 
     // If we have any leftover bytes, then the value is too large
     if num != 0 {
-        log::error!("Value is too large for {} bytes: {}", length, request.value);
-        return StatusCode::BAD_REQUEST;
+        return Err(AttrError::OutOfRange(format!(
+            "Value is too large for {} bytes: {}",
+            length, value
+        )));
     }
     // If all the bytes are 0xff, then the value is invalid
     if bytes.iter().all(|b| *b == 0xff) {
-        log::error!("Value is the invalid marker: {}", request.value);
-        return StatusCode::BAD_REQUEST;
+        return Err(AttrError::OutOfRange(format!(
+            "Value is the invalid marker: {}",
+            value
+        )));
     }
+    Ok(bytes)
+}
 
-    // Write the characteristic
-    attrs::write_characteristic(&state, uuid16, &bytes).await
+/// How long `verify` waits for the ornament to reflect a write.
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long `verify` waits between reads.
+const VERIFY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Read back the characteristic with the given `uuid` until it holds the
+/// `expected` value. Configuration characteristics are split into a write-only
+/// and a read-only half, and the ornament only copies the value into the
+/// read-only half once it's applied it. So, we have to give it some time. This
+/// catches writes that the ornament silently dropped.
+///
+/// Returns the value that was read back. If it never matched, returns the last
+/// value that was read, if any.
+pub async fn verify(
+    state: &ApplicationState,
+    uuid: CharUuid,
+    length: usize,
+    expected: u64,
+) -> Result<u64, Option<u64>> {
+    let deadline = tokio::time::Instant::now() + VERIFY_TIMEOUT;
+    let mut last = None;
+    loop {
+        // Only configuration characteristics get verified, and those always
+        // have the marker
+        match read(state, uuid, length, true).await {
+            Ok(v) if v == expected => return Ok(v),
+            Ok(v) => last = Some(v),
+            Err(_) => (),
+        }
+
+        if tokio::time::Instant::now() >= deadline {
+            log::error!(
+                uuid:% = uuid;
+                "Characteristic {} was not updated to {}: last read {:?}",
+                uuid,
+                expected,
+                last
+            );
+            return Err(last);
+        }
+        tokio::time::sleep(VERIFY_INTERVAL).await;
+    }
+}
+
+/// Generic method for `DELETE` requests. This writes the invalid marker (all
+/// 0xff bytes) to the characteristic, which puts it back into the "not yet set"
+/// state. The ornament ignores configuration characteristics in this state.
+pub async fn delete(
+    state: ApplicationState,
+    uuid: CharUuid,
+    length: usize,
+) -> Result<(), AttrError> {
+    let bytes = vec![0xffu8; length];
+    attrs::write_characteristic(&state, uuid, &bytes).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+
+    use super::*;
+    use crate::attrs::testing;
+    use crate::ble;
+
+    /// The 4-byte heap characteristic.
+    const HEAP_UUID: CharUuid = CharUuid::short(0x0002);
+
+    #[tokio::test]
+    async fn lenient_length_truncates_long_values() {
+        let ornament = testing::ornament();
+        ornament.set(HEAP_UUID, &[0x00, 0x01, 0x02, 0x03, 0xaa, 0xbb]);
+        let mut state = testing::state(ornament.clone());
+
+        // Strict by default, and the error says what the lengths were
+        let app = testing::app(state.clone());
+        let (status, body) = testing::request(&app, Method::GET, "/heap", None).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body["expected"], 4);
+        assert_eq!(body["actual"], 6);
+
+        // Lenient keeps the first bytes
+        state.lenient_length = true;
+        let app = testing::app(state.clone());
+        let (status, body) = testing::request(&app, Method::GET, "/heap", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["value"], 0x00010203);
+        assert_eq!(read(&state, HEAP_UUID, 4, true).await.unwrap(), 0x00010203);
+    }
+
+    #[tokio::test]
+    async fn lenient_length_still_rejects_short_values() {
+        let ornament = testing::ornament();
+        ornament.set(HEAP_UUID, &[0x00, 0x01]);
+        let mut state = testing::state(ornament);
+        state.lenient_length = true;
+        match read(&state, HEAP_UUID, 4, true).await {
+            Err(AttrError::BadLength {
+                expected: 4,
+                actual: 2,
+                ..
+            }) => (),
+            r => panic!("Expected a bad length, but got {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn all_ones_is_unset_only_with_the_marker() {
+        let ornament = testing::ornament();
+        ornament.set(HEAP_UUID, &[0xff; 4]);
+        let state = testing::state(ornament);
+
+        match read(&state, HEAP_UUID, 4, true).await {
+            Err(AttrError::Unset { .. }) => (),
+            r => panic!("Expected the value to be unset, but got {:?}", r),
+        }
+        assert_eq!(read(&state, HEAP_UUID, 4, false).await.unwrap(), 0xffffffff);
+    }
+
+    #[tokio::test]
+    async fn attributes_use_their_marker_setting() {
+        let ornament = testing::ornament();
+        let app = testing::app(testing::state(ornament.clone()));
+
+        // The boot count reserves all ones for unset
+        let (status, _) = testing::request(&app, Method::GET, "/bootcount", None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        // So does the battery level, which only goes up to 100%
+        let (status, _) = testing::request(&app, Method::GET, "/battery/level", None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        ornament.set(ble::BATTERY_LEVEL_UUID, &[87]);
+        let (status, body) = testing::request(&app, Method::GET, "/battery/level", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["value"], 87);
+    }
 }
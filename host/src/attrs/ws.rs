@@ -0,0 +1,269 @@
+//! A WebSocket channel for controlling the ornament over a single persistent
+//! connection. Each text message is a JSON `Command`. The commands just send
+//! requests to the router, so they behave exactly like the HTTP routes.
+//!
+//! Subscriptions poll the attribute on an interval and send an `update` frame
+//! for each reading. They are cancelled when the socket closes. If the client
+//! falls behind, updates are dropped rather than queued.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::http::{Method, StatusCode};
+use axum::response::Response;
+use axum::Router;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::AbortHandle;
+use tower_http::timeout::TimeoutLayer;
+
+use crate::attrs;
+use crate::attrs::ApplicationState;
+
+/// How often to poll subscribed attributes if the command doesn't say.
+const DEFAULT_INTERVAL_MS: u64 = 1000;
+
+/// The most often subscribed attributes can be polled. Every poll is a BLE read,
+/// so polling faster than this would crowd out everything else.
+const MIN_INTERVAL_MS: u64 = 100;
+
+/// How many subscription updates can be waiting to go out on a socket. Past
+/// this, new updates are dropped until the client catches up.
+const UPDATE_QUEUE: usize = 16;
+
+/// What a `Command` should do.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Op {
+    /// `GET` the attribute.
+    Get,
+    /// `POST` the `value` to the attribute.
+    Set,
+    /// Start polling the attribute every `interval_ms`, but no more often than
+    /// every `MIN_INTERVAL_MS`.
+    Subscribe,
+    /// Stop polling the attribute.
+    Unsubscribe,
+    /// Only used in responses, for a reading from a subscription.
+    Update,
+}
+
+/// A message from the client. Attributes are named as in
+/// `attrs::attribute_path`.
+#[derive(Deserialize)]
+struct Command {
+    op: Op,
+    attr: String,
+    #[serde(default)]
+    value: Option<serde_json::Value>,
+    #[serde(default)]
+    interval_ms: Option<u64>,
+}
+
+/// A message to the client in response to a `Command`, or for a subscription.
+/// The `status` and `body` are what the equivalent HTTP request returned.
+#[derive(Serialize)]
+struct Reply {
+    op: Op,
+    attr: String,
+    status: u16,
+    body: serde_json::Value,
+}
+
+/// A message to the client when we couldn't handle its message at all.
+#[derive(Serialize)]
+struct ErrorFrame {
+    error: String,
+}
+
+/// Upgrade the connection to a WebSocket.
+pub async fn get_ws(State(state): State<ApplicationState>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(move |socket| handle_socket(socket, state))
+}
+
+/// Serve `Command`s on the `socket` until it closes.
+async fn handle_socket(mut socket: WebSocket, state: ApplicationState) {
+    // Commands get the same timeout as the HTTP routes
+    let app = attrs::router(state.read_only)
+        .layer(TimeoutLayer::with_status_code(
+            StatusCode::GATEWAY_TIMEOUT,
+            state.request_timeout,
+        ))
+        .with_state(state);
+
+    // Frames from subscriptions go through here, since the socket can only be
+    // written from this task
+    let (tx, mut rx) = mpsc::channel::<String>(UPDATE_QUEUE);
+    let mut subscriptions: HashMap<String, AbortHandle> = HashMap::new();
+
+    loop {
+        let frame = tokio::select! {
+            msg = socket.recv() => {
+                let text = match msg {
+                    Some(Ok(Message::Text(t))) => t,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                match serde_json::from_str::<Command>(&text) {
+                    Ok(cmd) => handle_command(cmd, &app, &tx, &mut subscriptions).await,
+                    Err(e) => {
+                        log::error!("Malformed WebSocket message: {:?}", e);
+                        Some(to_frame(&ErrorFrame { error: e.to_string() }))
+                    }
+                }
+            }
+            Some(frame) = rx.recv() => Some(frame),
+        };
+        if let Some(frame) = frame {
+            if socket.send(Message::Text(frame)).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    log::debug!("WebSocket closed");
+    for handle in subscriptions.values() {
+        handle.abort();
+    }
+}
+
+/// Do what the `cmd` says. Returns the frame to send back, if any.
+async fn handle_command(
+    cmd: Command,
+    app: &Router,
+    tx: &mpsc::Sender<String>,
+    subscriptions: &mut HashMap<String, AbortHandle>,
+) -> Option<String> {
+    let path = attrs::attribute_path(&cmd.attr);
+    match cmd.op {
+        Op::Get => Some(request(app, Op::Get, cmd.attr, Method::GET, &path, None).await),
+        Op::Set => {
+            let Some(value) = cmd.value else {
+                return Some(to_frame(&ErrorFrame {
+                    error: String::from("`set` requires a `value`"),
+                }));
+            };
+            Some(request(app, Op::Set, cmd.attr, Method::POST, &path, Some(value)).await)
+        }
+        Op::Subscribe => {
+            let interval = poll_interval(cmd.interval_ms);
+            let handle = tokio::spawn({
+                let app = app.clone();
+                let tx = tx.clone();
+                let attr = cmd.attr.clone();
+                async move {
+                    loop {
+                        let frame =
+                            request(&app, Op::Update, attr.clone(), Method::GET, &path, None).await;
+                        if !send_update(&tx, &attr, frame) {
+                            return;
+                        }
+                        tokio::time::sleep(interval).await;
+                    }
+                }
+            })
+            .abort_handle();
+            // Resubscribing replaces the old subscription
+            if let Some(old) = subscriptions.insert(cmd.attr, handle) {
+                old.abort();
+            }
+            None
+        }
+        Op::Unsubscribe => {
+            if let Some(old) = subscriptions.remove(&cmd.attr) {
+                old.abort();
+            }
+            None
+        }
+        Op::Update => Some(to_frame(&ErrorFrame {
+            error: String::from("`update` is not a command"),
+        })),
+    }
+}
+
+/// Queue an update `frame` for the subscription to `attr`, dropping it if the
+/// queue is full. Returns whether the socket is still open.
+fn send_update(tx: &mpsc::Sender<String>, attr: &str, frame: String) -> bool {
+    match tx.try_send(frame) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            log::warn!(
+                "Dropping an update for {}, since the client is behind",
+                attr
+            );
+            true
+        }
+        Err(TrySendError::Closed(_)) => false,
+    }
+}
+
+/// How often to poll for a subscription that asked for every `interval_ms`.
+/// Intervals that are too short are raised to `MIN_INTERVAL_MS`.
+fn poll_interval(interval_ms: Option<u64>) -> Duration {
+    let interval_ms = interval_ms.unwrap_or(DEFAULT_INTERVAL_MS);
+    if interval_ms < MIN_INTERVAL_MS {
+        log::debug!(
+            "Raising subscription interval from {}ms to {}ms",
+            interval_ms,
+            MIN_INTERVAL_MS
+        );
+    }
+    Duration::from_millis(interval_ms.max(MIN_INTERVAL_MS))
+}
+
+/// Send a request for the attribute `attr` to the `app`, and turn the response
+/// into a `Reply` frame.
+async fn request(
+    app: &Router,
+    op: Op,
+    attr: String,
+    method: Method,
+    path: &str,
+    body: Option<serde_json::Value>,
+) -> String {
+    match attrs::dispatch(app.clone(), method, path, body).await {
+        Ok((status, body)) => to_frame(&Reply {
+            op,
+            attr,
+            status: status.as_u16(),
+            body,
+        }),
+        Err(e) => to_frame(&ErrorFrame {
+            error: e.to_string(),
+        }),
+    }
+}
+
+/// Serialize a frame to send to the client.
+fn to_frame<T: Serialize>(frame: &T) -> String {
+    serde_json::to_string(frame).expect("Frames are always serializable")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intervals_have_a_floor() {
+        let ms = Duration::from_millis;
+        assert_eq!(poll_interval(None), ms(DEFAULT_INTERVAL_MS));
+        assert_eq!(poll_interval(Some(0)), ms(MIN_INTERVAL_MS));
+        assert_eq!(poll_interval(Some(1)), ms(MIN_INTERVAL_MS));
+        assert_eq!(poll_interval(Some(250)), ms(250));
+    }
+
+    #[test]
+    fn updates_are_dropped_when_the_client_is_behind() {
+        let (tx, mut rx) = mpsc::channel(1);
+        assert!(send_update(&tx, "light", String::from("first")));
+        assert!(send_update(&tx, "light", String::from("second")));
+        assert_eq!(rx.try_recv().unwrap(), "first");
+        assert!(rx.try_recv().is_err());
+
+        drop(rx);
+        assert!(!send_update(&tx, "light", String::from("third")));
+    }
+}
@@ -1,58 +1,289 @@
 //! Module housing all the functionality needed to communicate with the
 //! christmas ornament over BLE.
 
+use std::fmt;
 use std::time::Duration;
 
 use anyhow::{Context, Result};
 use btleplug::api::{
-    Central, Characteristic, Manager as _, Peripheral as _, ScanFilter, Service, WriteType,
+    Central, CentralEvent, CentralState, CharPropFlags, Characteristic, Manager as _,
+    Peripheral as _, ScanFilter, Service, WriteType,
 };
 use btleplug::platform::{Adapter, Manager, Peripheral};
+use futures::StreamExt;
+use serde::{Serialize, Serializer};
+use tokio::time::Instant;
 use uuid::Uuid;
 
 static ORNAMENT_SERVICE_UUID: Uuid = Uuid::from_u128(0x895225feacaf4f21b0e71adb51e11653u128);
-static BLE_BASE_UUID: Uuid = Uuid::from_u128(0x0000000000001000800000805f9b34fbu128);
+pub const BLE_BASE_UUID: Uuid = Uuid::from_u128(0x0000000000001000800000805f9b34fbu128);
 
-/// Convert a 16-bit UUID to a 128-bit UUID. All characteristics use 16-bit
-/// UUIDs since the Bluefruit SPI Friend only supports those.
-pub fn uuid_16(uuid16: u16) -> Uuid {
-    Uuid::from_u128(BLE_BASE_UUID.as_u128() + (Into::<u128>::into(uuid16) << 96))
+/// The Battery Level characteristic, on the standard Battery Service.
+pub const BATTERY_LEVEL_UUID: CharUuid = CharUuid::short(0x2a19);
+
+/// The standard characteristics we read, along with the standard services they're
+/// on. Newer firmware has these services alongside its own. So far, this is
+/// just the Battery Level on the Battery Service.
+static STANDARD_CHARACTERISTICS: [(CharUuid, Uuid); 1] =
+    [(BATTERY_LEVEL_UUID, uuid_16_on(0x180f, BLE_BASE_UUID))];
+
+/// How often to tell the user how scanning is going.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Convert a 16-bit UUID to a 128-bit UUID on the given `base`. All of the
+/// ornament's own characteristics use 16-bit UUIDs on the Bluetooth base UUID,
+/// since the Bluefruit SPI Friend only supports those. Vendors that use 16-bit
+/// UUIDs have their own base.
+pub const fn uuid_16_on(uuid16: u16, base: Uuid) -> Uuid {
+    Uuid::from_u128(base.as_u128() + ((uuid16 as u128) << 96))
 }
 
-/// Connect to the christmas ornament, given its display `name`.
-pub async fn connect(name: &str, scan_duration: Duration) -> Result<Peripheral> {
-    // See: https://github.com/deviceplug/btleplug/blob/master/examples/discover_adapters_peripherals.rs
+/// Get the 16-bit UUID back from a 128-bit UUID, if it's on the Bluetooth base.
+/// Returns `None` for UUIDs that aren't based on the Bluetooth base UUID.
+pub fn uuid16_of(uuid: Uuid) -> Option<u16> {
+    let offset = uuid.as_u128().wrapping_sub(BLE_BASE_UUID.as_u128());
+    if offset & !(0xffffu128 << 96) != 0 {
+        return None;
+    }
+    Some((offset >> 96) as u16)
+}
+
+/// Which characteristic to use, as a 16-bit UUID on a `base`. Most are on the
+/// Bluetooth base UUID, like the ornament's own, and vendors that use 16-bit
+/// UUIDs have their own base. A characteristic with only a full 128-bit UUID is
+/// `uuid16` zero on that UUID as its `base`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CharUuid {
+    pub uuid16: u16,
+    pub base: Uuid,
+}
+
+impl CharUuid {
+    /// The characteristic with the 16-bit UUID `uuid16` on the Bluetooth base
+    /// UUID.
+    pub const fn short(uuid16: u16) -> Self {
+        CharUuid {
+            uuid16,
+            base: BLE_BASE_UUID,
+        }
+    }
+
+    /// The full 128-bit UUID of the characteristic.
+    pub const fn uuid(self) -> Uuid {
+        uuid_16_on(self.uuid16, self.base)
+    }
+}
+
+/// Characteristics on the Bluetooth base UUID are just their 16-bit UUID in
+/// hex. Everything else is the full UUID.
+impl fmt::Display for CharUuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.base {
+            BLE_BASE_UUID => write!(f, "{:04x}", self.uuid16),
+            _ => write!(f, "{}", self.uuid()),
+        }
+    }
+}
+
+/// This is the `uuid16`, along with the `base` if it isn't the Bluetooth base
+/// UUID. That way, the ornament's own characteristics look like they do in its
+/// firmware.
+impl Serialize for CharUuid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Repr {
+            uuid16: u16,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            base: Option<String>,
+        }
+        Repr {
+            uuid16: self.uuid16,
+            base: (self.base != BLE_BASE_UUID).then(|| self.base.to_string()),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// How to compare the ornament's display name against the names peripherals
+/// advertise.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NameMatch {
+    /// Ignore surrounding whitespace and case. Different firmware builds aren't
+    /// consistent about either.
+    Loose,
+    /// The names have to be exactly the same.
+    Exact,
+    /// The advertised name has to start with the display name. This is for
+    /// connecting to any one of several ornaments named alike.
+    Prefix,
+}
+
+impl NameMatch {
+    /// Check whether the `advertised` name matches the display `name`.
+    fn matches(self, name: &str, advertised: &str) -> bool {
+        match self {
+            NameMatch::Loose => name.trim().to_lowercase() == advertised.trim().to_lowercase(),
+            NameMatch::Exact => name == advertised,
+            NameMatch::Prefix => advertised.starts_with(name),
+        }
+    }
+
+    /// Say why the `advertised` name doesn't match the display `name`, if it
+    /// would have ignoring whitespace and case. Those are probably what the
+    /// user meant, so they'll want to know why we skipped them.
+    fn near_miss(self, name: &str, advertised: &str) -> Option<String> {
+        let loose = |n: &str| n.trim().to_lowercase();
+        match self {
+            NameMatch::Loose => None,
+            NameMatch::Exact => NameMatch::Loose
+                .matches(name, advertised)
+                .then(|| String::from("not an exact match")),
+            NameMatch::Prefix => loose(advertised)
+                .starts_with(&loose(name))
+                .then(|| format!("does not start with exactly {:?}", name)),
+        }
+    }
+}
+
+/// Which peripherals are close enough to consider, by their signal strength.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RssiFilter {
+    /// The weakest RSSI to accept, in dBm, if any.
+    pub min: Option<i16>,
+    /// Whether to skip peripherals we don't have an RSSI for. Otherwise, they
+    /// pass regardless of `min`.
+    pub required: bool,
+}
+
+impl RssiFilter {
+    pub fn accepts(&self, rssi: Option<i16>) -> bool {
+        match (rssi, self.min) {
+            (None, _) => !self.required,
+            (Some(rssi), Some(min)) => rssi >= min,
+            (Some(_), None) => true,
+        }
+    }
+}
 
-    // Get a list of BLE adapters from the OS
+/// Get the OS's BLE adapters. Their order is what `connect` indexes them by.
+pub async fn adapters() -> Result<Vec<Adapter>> {
     let manager = Manager::new()
         .await
         .context("Failed to retreive bluetooth manager")?;
-    let adapters = manager
+    manager
         .adapters()
         .await
-        .context("Failed to retreive bluetooth adapters")?;
-    // We don't know which adapter to use, and we don't have a
-    // platform-independent way of getting the user to choose, so we'll just use
-    // the first one
-    let adapter = adapters.first().context("No bluetooth adapters found")?;
+        .context("Failed to retreive bluetooth adapters")
+}
+
+/// Whether a BLE adapter can be used right now. Turning on airplane mode, for
+/// example, powers the adapter off or removes it entirely, depending on the
+/// platform.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AdapterStatus {
+    Available,
+    PoweredOff,
+    Missing,
+}
+
+impl fmt::Display for AdapterStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AdapterStatus::Available => "available",
+            AdapterStatus::PoweredOff => "powered off",
+            AdapterStatus::Missing => "missing",
+        })
+    }
+}
+
+/// Check whether the `adapter` at that index in `adapters` can be used. If the
+/// platform can't tell whether it's powered on, we assume it is, and let
+/// connecting find out otherwise.
+pub async fn adapter_status(adapter: usize) -> AdapterStatus {
+    let adapters = match adapters().await {
+        Ok(a) => a,
+        Err(e) => {
+            log::debug!("Could not list bluetooth adapters: {:?}", e);
+            return AdapterStatus::Missing;
+        }
+    };
+    let Some(adapter) = adapters.get(adapter) else {
+        return AdapterStatus::Missing;
+    };
+    status_of(adapter.adapter_state().await)
+}
+
+/// What an adapter's `state` means for whether we can use it. Only an adapter
+/// that says it's powered off isn't available. Unknown states and errors get
+/// the benefit of the doubt, since some platforms can't tell.
+fn status_of(state: btleplug::Result<CentralState>) -> AdapterStatus {
+    match state {
+        Ok(CentralState::PoweredOff) => AdapterStatus::PoweredOff,
+        Ok(_) => AdapterStatus::Available,
+        Err(e) => {
+            log::debug!("Could not get the bluetooth adapter's state: {:?}", e);
+            AdapterStatus::Available
+        }
+    }
+}
+
+/// Connect to the christmas ornament, given its display `name`, using the
+/// `adapter` at that index in `adapters`. If several
+/// peripherals match, we pick the one with the strongest signal, unless we're
+/// told to be `strict` about it. Peripherals that don't pass the `rssi` filter
+/// aren't considered at all. See `try_find`.
+///
+/// If given a `warmup` characteristic, we read it once before returning. See
+/// `warm_up`.
+pub async fn connect(
+    adapter: usize,
+    name: &str,
+    name_match: NameMatch,
+    strict: bool,
+    rssi: RssiFilter,
+    scan_duration: Duration,
+    warmup: Option<CharUuid>,
+) -> Result<Peripheral> {
+    // See: https://github.com/deviceplug/btleplug/blob/master/examples/discover_adapters_peripherals.rs
+
+    // Get a list of BLE adapters from the OS, and pick the one we were told to.
+    // Indices are the only platform-independent way to name them.
+    let adapters = adapters().await?;
+    if adapters.is_empty() {
+        anyhow::bail!("No bluetooth adapters found");
+    }
+    let adapter = adapters.get(adapter).with_context(|| {
+        format!(
+            "No bluetooth adapter {}, since there are only {}",
+            adapter,
+            adapters.len()
+        )
+    })?;
 
     // See if we can find the ornament before we start scanning
-    let mut ornament = try_find(name, adapter).await?;
+    let mut ornament = try_find(name, name_match, strict, rssi, adapter).await?;
 
-    // If we didn't find the ornament, scan for it
-    if ornament.is_none() {
-        log::debug!("Could not find the christmas ornament in pre-existing peripherals");
+    // If we didn't find the ornament, scan for it. When `strict`, we always
+    // scan, since another peripheral with the same name may not have been seen
+    // yet
+    if ornament.is_none() || strict {
+        match ornament {
+            None => {
+                log::debug!("Could not find the christmas ornament in pre-existing peripherals")
+            }
+            Some(_) => log::debug!("Scanning anyway, to check that only one peripheral matches"),
+        }
         log::info!("Starting to scan for peripherals");
 
         adapter
             .start_scan(ScanFilter::default())
             .await
             .context("Failed to start scan")?;
-        tokio::time::sleep(scan_duration).await;
+        scan(adapter, scan_duration, name, name_match, rssi, strict).await;
         adapter.stop_scan().await.context("Failed to stop scan")?;
         log::info!("Done scanning for peripherals");
 
-        ornament = try_find(name, adapter).await?;
+        ornament = try_find(name, name_match, strict, rssi, adapter).await?;
     }
 
     // If we still didn't find the ornament, give up
@@ -75,34 +306,225 @@ pub async fn connect(name: &str, scan_duration: Duration) -> Result<Peripheral>
         .context("Could not discover services")?;
     log::debug!("Discovered services on the christmas ornament");
 
+    if let Some(uuid) = warmup {
+        warm_up(&ornament, uuid).await;
+    }
+
     log::info!("Connected to the christmas ornament");
     Ok(ornament)
 }
 
+/// Read the characteristic `uuid` on the `ornament` and throw the value away.
+/// The first read after connecting is often slow or fails outright, since the
+/// OS's GATT cache is cold. It's better for this read to take that hit than a
+/// client's. So, this should be something cheap to read. Failing is fine, and
+/// is only logged.
+async fn warm_up(ornament: &Peripheral, uuid: CharUuid) {
+    let characteristics = ornament.characteristics();
+    let Some(characteristic) = characteristics.iter().find(|c| c.uuid == uuid.uuid()) else {
+        log::warn!(uuid:% = uuid; "Could not find characteristic {} to warm up with", uuid);
+        return;
+    };
+    match read_characteristic(ornament, characteristic).await {
+        Ok(_) => log::debug!("Warmed up the connection by reading {}", uuid),
+        Err(e) => log::warn!(uuid:% = uuid; "Warm-up read of {} failed: {:?}", uuid, e),
+    }
+}
+
+/// Wait for the `adapter` to scan for `scan_duration`, telling the user how
+/// it's going every second. If the platform gives us the adapter's events, we
+/// also log every peripheral as it's discovered, and stop as soon as one that
+/// `try_find` would pick shows up. That is, it matches the display `name` and
+/// the `rssi` filter accepts it. Otherwise, we always wait the full duration.
+///
+/// If `strict` is set, we also wait the full duration, since only then can we
+/// tell whether more than one peripheral matches.
+async fn scan(
+    adapter: &Adapter,
+    scan_duration: Duration,
+    name: &str,
+    name_match: NameMatch,
+    rssi: RssiFilter,
+    strict: bool,
+) {
+    let mut events = match adapter.events().await {
+        Ok(e) => Some(e),
+        Err(e) => {
+            log::debug!("Could not get adapter events: {:?}", e);
+            None
+        }
+    };
+
+    let deadline = Instant::now() + scan_duration;
+    let mut progress =
+        tokio::time::interval_at(Instant::now() + PROGRESS_INTERVAL, PROGRESS_INTERVAL);
+    loop {
+        // Without an event stream, only the timers can wake us up
+        let event = async {
+            match events.as_mut() {
+                Some(e) => e.next().await,
+                None => std::future::pending().await,
+            }
+        };
+        let (id, discovered) = tokio::select! {
+            _ = tokio::time::sleep_until(deadline) => return,
+            _ = progress.tick() => {
+                let seen = adapter.peripherals().await.map(|p| p.len()).unwrap_or(0);
+                log::info!(
+                    "Scanning: {}s left, {} peripherals seen so far",
+                    deadline.saturating_duration_since(Instant::now()).as_secs(),
+                    seen
+                );
+                continue;
+            }
+            e = event => match e {
+                Some(CentralEvent::DeviceDiscovered(id)) => (id, true),
+                // Names often come in after the peripheral is discovered
+                Some(CentralEvent::DeviceUpdated(id)) => (id, false),
+                Some(_) => continue,
+                // The stream ended, so fall back to just the timers
+                None => {
+                    events = None;
+                    continue;
+                }
+            },
+        };
+
+        let props = match adapter.peripheral(&id).await {
+            Ok(p) => p.properties().await.ok().flatten(),
+            Err(_) => None,
+        };
+        if discovered {
+            match &props {
+                Some(props) => log::info!(
+                    "Discovered {} ({})",
+                    props.local_name.as_deref().unwrap_or("unnamed peripheral"),
+                    props.address
+                ),
+                None => log::info!("Discovered {:?}", id),
+            }
+        }
+        let Some(props) = props else {
+            continue;
+        };
+        let advertised = props.local_name.as_deref();
+        if !strict && is_candidate(name, name_match, rssi, advertised, props.rssi) {
+            log::info!("Found the christmas ornament, so stopping the scan early");
+            return;
+        }
+    }
+}
+
+/// Whether a peripheral `advertised` under that name, with signal strength
+/// `signal`, could be the ornament. Its name has to match the display `name`,
+/// and the `rssi` filter has to accept it.
+fn is_candidate(
+    name: &str,
+    name_match: NameMatch,
+    rssi: RssiFilter,
+    advertised: Option<&str>,
+    signal: Option<i16>,
+) -> bool {
+    advertised.is_some_and(|a| name_match.matches(name, a)) && rssi.accepts(signal)
+}
+
 /// Try to find the christmas ornament in the list of peripherals returned by
-/// the `adapter`, given its display `name`. May fail. If successful, returns
-/// the `Peripheral`, or `None` if it doesn't exist.
-async fn try_find(name: &str, adapter: &Adapter) -> Result<Option<Peripheral>> {
+/// the `adapter`, given its display `name` and how to compare it. May fail. If
+/// successful, returns the `Peripheral`, or `None` if it doesn't exist.
+///
+/// If more than one peripheral matches, we log all of them and pick the one
+/// with the strongest signal, since it's probably the closest. Peripherals we
+/// don't have an RSSI for lose. If `strict` is set, we fail instead. Either
+/// way, peripherals that the `rssi` filter rejects are skipped first.
+async fn try_find(
+    name: &str,
+    name_match: NameMatch,
+    strict: bool,
+    rssi: RssiFilter,
+    adapter: &Adapter,
+) -> Result<Option<Peripheral>> {
     // Extract the peripheral list from the adapter
     let peripherals = adapter
         .peripherals()
         .await
         .context("Could not to get pre-existing peripherals")?;
 
-    // Try to find a peripheral with the given name
+    // Find all the peripherals with the given name
+    let mut candidates = Vec::new();
     for periph in peripherals {
+        // Some adapters don't have properties for peripherals they've only
+        // just seen, so skip those rather than give up on all the others
         let props = periph
             .properties()
             .await
-            .context("Could not get peripheral properties")?
-            .context("No peripheral properties available")?;
-        if props.local_name == Some(name.to_string()) {
-            log::debug!("Found the christmas ornament: {:?}", periph);
-            return Ok(Some(periph));
+            .context("Could not get peripheral properties")?;
+        let Some(props) = props else {
+            log::debug!("Skipping peripheral with no properties: {:?}", periph);
+            continue;
+        };
+        let Some(advertised) = props.local_name else {
+            continue;
+        };
+        if name_match.matches(name, &advertised) {
+            if !rssi.accepts(props.rssi) {
+                match (props.rssi, rssi.min) {
+                    (Some(r), Some(min)) => log::debug!(
+                        "Skipping peripheral named {:?} at {}: RSSI {} is below {}",
+                        advertised,
+                        props.address,
+                        r,
+                        min
+                    ),
+                    _ => log::debug!(
+                        "Skipping peripheral named {:?} at {}: no RSSI",
+                        advertised,
+                        props.address
+                    ),
+                }
+                continue;
+            }
+            log::info!(
+                "Found the christmas ornament as {:?} at {} - RSSI {:?}",
+                advertised,
+                props.address,
+                props.rssi
+            );
+            candidates.push((periph, advertised, props.address, props.rssi));
+            continue;
+        }
+        // Tell the user about names that would have matched loosely, in case
+        // they're wondering why we didn't pick them
+        if let Some(reason) = name_match.near_miss(name, &advertised) {
+            log::info!("Skipping peripheral named {:?}: {}", advertised, reason);
         }
     }
-    // Couldn't find it, but that's not an error
-    Ok(None)
+
+    if candidates.len() > 1 {
+        log::warn!(
+            "Found {} peripherals matching {:?}:",
+            candidates.len(),
+            name
+        );
+        for (_, advertised, address, rssi) in candidates.iter() {
+            log::warn!("    {:?} at {} - RSSI {:?}", advertised, address, rssi);
+        }
+        if strict {
+            anyhow::bail!("Found more than one peripheral matching {:?}", name);
+        }
+    }
+    // Pick the strongest. If there are none, we couldn't find it, but that's
+    // not an error
+    let Some((periph, advertised, address, _)) =
+        candidates.into_iter().max_by_key(|(_, _, _, rssi)| *rssi)
+    else {
+        return Ok(None);
+    };
+    log::info!(
+        "Picked the christmas ornament {:?} at {}",
+        advertised,
+        address
+    );
+    Ok(Some(periph))
 }
 
 /// Get the service with the ornament's service UUID from the `ornament`. Fails
@@ -116,10 +538,86 @@ pub fn get_service(ornament: &Peripheral) -> Result<Service> {
         .cloned()
 }
 
-pub fn find_characteristic(service: &Service, uuid: Uuid) -> Option<&Characteristic> {
-    service.characteristics.iter().find(|c| c.uuid == uuid)
+/// Get the standard services that the `ornament` has, out of the ones some
+/// attributes read from. Older firmware doesn't have any of them, so this just
+/// logs which ones are missing.
+pub fn get_standard_services(ornament: &Peripheral) -> Vec<Service> {
+    let services = ornament.services();
+    let mut uuids: Vec<Uuid> = STANDARD_CHARACTERISTICS.iter().map(|(_, s)| *s).collect();
+    uuids.dedup();
+    uuids
+        .iter()
+        .filter_map(|uuid| {
+            let service = services.iter().find(|s| s.uuid == *uuid);
+            if service.is_none() {
+                log::info!(
+                    "The christmas ornament does not have the standard service {}",
+                    uuid
+                );
+            }
+            service.cloned()
+        })
+        .collect()
+}
+
+/// Log all the characteristics on the `service`, and check which of the
+/// `expected` characteristics are on it. Returns how many of them were found.
+/// This is meant to catch bad firmware at startup, rather than on the first
+/// request.
+pub fn check_characteristics(service: &Service, expected: &[CharUuid]) -> usize {
+    log::info!(
+        "Service has {} characteristics",
+        service.characteristics.len()
+    );
+    for c in service.characteristics.iter() {
+        log::info!("    {} - {:?}", c.uuid, c.properties);
+    }
+
+    let mut found = 0;
+    for uuid in expected {
+        if find_characteristic(service, *uuid).is_some() {
+            found += 1;
+        } else {
+            log::warn!(uuid:% = uuid; "Missing characteristic {}", uuid);
+        }
+    }
+    found
 }
 
+/// Find the characteristic `uuid` on whichever service it belongs to. Standard
+/// characteristics are only looked for on their own service, out of the
+/// `standard` ones. Everything else is on the ornament's `service`. That way,
+/// a standard UUID on the wrong service isn't mistaken for the real one.
+pub fn find_characteristic_on<'a>(
+    service: &'a Service,
+    standard: &'a [Service],
+    uuid: CharUuid,
+) -> Option<&'a Characteristic> {
+    let standard_service = STANDARD_CHARACTERISTICS
+        .iter()
+        .find(|(c, _)| *c == uuid)
+        .map(|(_, s)| *s);
+    match standard_service {
+        Some(s) => standard
+            .iter()
+            .find(|service| service.uuid == s)
+            .and_then(|service| find_characteristic(service, uuid)),
+        None => find_characteristic(service, uuid),
+    }
+}
+
+/// Find the characteristic `uuid` on the `service`, if it's there.
+pub fn find_characteristic(service: &Service, uuid: CharUuid) -> Option<&Characteristic> {
+    service
+        .characteristics
+        .iter()
+        .find(|c| c.uuid == uuid.uuid())
+}
+
+/// Read the `characteristic`'s value with a single read. We don't do GATT long
+/// reads ourselves, and `btleplug` has no way to read at an offset. On every
+/// platform it supports, the OS's BLE stack does the long read for values longer
+/// than the MTU and hands us the whole thing.
 pub async fn read_characteristic(
     ornament: &Peripheral,
     characteristic: &Characteristic,
@@ -130,13 +628,221 @@ pub async fn read_characteristic(
         .context("Could not read characteristic")
 }
 
+/// Choose how to write to the `characteristic`, given what it supports.
+///
+/// Writing with response means the ornament acknowledges each write, so we find
+/// out if it failed, but each one costs a round trip. Everything we write is
+/// configuration or a command, which should be acknowledged, so we write with
+/// response unless the characteristic only supports writing without.
+pub fn write_type(characteristic: &Characteristic) -> WriteType {
+    let with_response = characteristic.properties.contains(CharPropFlags::WRITE);
+    let without_response = characteristic
+        .properties
+        .contains(CharPropFlags::WRITE_WITHOUT_RESPONSE);
+    match !with_response && without_response {
+        true => WriteType::WithoutResponse,
+        false => WriteType::WithResponse,
+    }
+}
+
 pub async fn write_characteristic(
     ornament: &Peripheral,
     characteristic: &Characteristic,
     value: &[u8],
+    write_type: WriteType,
 ) -> Result<()> {
     ornament
-        .write(characteristic, value, WriteType::WithoutResponse)
+        .write(characteristic, value, write_type)
         .await
         .context("Could not write characteristic")
 }
+
+/// The mechanism the ornament uses to tell us about changes to a
+/// characteristic's value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Subscription {
+    /// Notifications, which the ornament sends without waiting for an
+    /// acknowledgement.
+    Notify,
+    /// Indications, which have to be confirmed before the ornament sends the
+    /// next one. The OS's BLE stack sends the confirmations for us.
+    Indicate,
+}
+
+/// Subscribe to changes to the `characteristic`'s value. If it supports both
+/// indications and notifications, we prefer indications since they're reliable.
+/// This matches what `btleplug` does. Fails if the characteristic supports
+/// neither.
+///
+/// The new values are delivered through the `ornament`'s notification stream.
+pub async fn subscribe(
+    ornament: &Peripheral,
+    characteristic: &Characteristic,
+) -> Result<Subscription> {
+    let subscription = if characteristic.properties.contains(CharPropFlags::INDICATE) {
+        Subscription::Indicate
+    } else if characteristic.properties.contains(CharPropFlags::NOTIFY) {
+        Subscription::Notify
+    } else {
+        anyhow::bail!(
+            "Characteristic {} supports neither notifications nor indications",
+            characteristic.uuid
+        );
+    };
+
+    ornament
+        .subscribe(characteristic)
+        .await
+        .context("Could not subscribe to characteristic")?;
+    Ok(subscription)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    /// A characteristic that supports only the write types in `properties`.
+    fn characteristic(properties: CharPropFlags) -> Characteristic {
+        Characteristic {
+            uuid: CharUuid::short(0x0008).uuid(),
+            service_uuid: ORNAMENT_SERVICE_UUID,
+            properties,
+            descriptors: BTreeSet::new(),
+        }
+    }
+
+    /// A service with the characteristics `uuids`.
+    fn service(uuid: Uuid, uuids: &[CharUuid]) -> Service {
+        Service {
+            uuid,
+            primary: true,
+            characteristics: uuids
+                .iter()
+                .map(|c| Characteristic {
+                    uuid: c.uuid(),
+                    service_uuid: uuid,
+                    properties: CharPropFlags::READ,
+                    descriptors: BTreeSet::new(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn char_uuids_cover_full_uuids() {
+        let short = CharUuid::short(0x2a19);
+        assert_eq!(short.to_string(), "2a19");
+        assert_eq!(uuid16_of(short.uuid()), Some(0x2a19));
+
+        let full = CharUuid {
+            uuid16: 0,
+            base: ORNAMENT_SERVICE_UUID,
+        };
+        assert_eq!(full.uuid(), ORNAMENT_SERVICE_UUID);
+        assert_eq!(full.to_string(), ORNAMENT_SERVICE_UUID.to_string());
+    }
+
+    #[test]
+    fn char_uuids_serialize_without_the_bluetooth_base() {
+        let short = serde_json::to_value(CharUuid::short(0x0002)).unwrap();
+        assert_eq!(short, serde_json::json!({"uuid16": 2}));
+
+        let vendor = CharUuid {
+            uuid16: 0x0002,
+            base: ORNAMENT_SERVICE_UUID,
+        };
+        let vendor = serde_json::to_value(vendor).unwrap();
+        let expected = serde_json::json!({"uuid16": 2, "base": ORNAMENT_SERVICE_UUID.to_string()});
+        assert_eq!(vendor, expected);
+    }
+
+    #[test]
+    fn standard_characteristics_are_only_on_their_service() {
+        let level = BATTERY_LEVEL_UUID;
+        let bootcount = CharUuid::short(0x0010);
+        let battery = uuid_16_on(0x180f, BLE_BASE_UUID);
+        let other = uuid_16_on(0x1800, BLE_BASE_UUID);
+        let ornament = service(ORNAMENT_SERVICE_UUID, &[bootcount, level]);
+
+        // Not on the ornament's service, or some other standard one
+        let standard = [service(other, &[level])];
+        assert!(find_characteristic_on(&ornament, &standard, level).is_none());
+
+        let standard = [service(other, &[level]), service(battery, &[level])];
+        let found = find_characteristic_on(&ornament, &standard, level).unwrap();
+        assert_eq!(found.service_uuid, battery);
+        let found = find_characteristic_on(&ornament, &standard, bootcount).unwrap();
+        assert_eq!(found.service_uuid, ORNAMENT_SERVICE_UUID);
+    }
+
+    #[test]
+    fn only_powered_off_adapters_are_unavailable() {
+        let cases = [
+            (Ok(CentralState::PoweredOn), AdapterStatus::Available),
+            (Ok(CentralState::Unknown), AdapterStatus::Available),
+            (Ok(CentralState::PoweredOff), AdapterStatus::PoweredOff),
+            (
+                Err(btleplug::Error::NotSupported(String::from("adapter state"))),
+                AdapterStatus::Available,
+            ),
+        ];
+        for (state, expected) in cases {
+            assert_eq!(status_of(state), expected);
+        }
+    }
+
+    #[test]
+    fn near_misses_are_worded_by_match_mode() {
+        let near_miss = |m: NameMatch, advertised| m.near_miss("Ornament", advertised);
+        assert_eq!(
+            near_miss(NameMatch::Exact, " ornament").as_deref(),
+            Some("not an exact match")
+        );
+        assert_eq!(
+            near_miss(NameMatch::Prefix, "ornament-den").as_deref(),
+            Some("does not start with exactly \"Ornament\"")
+        );
+        assert_eq!(near_miss(NameMatch::Exact, "Other"), None);
+        assert_eq!(near_miss(NameMatch::Prefix, "Other"), None);
+        assert_eq!(near_miss(NameMatch::Loose, "Other"), None);
+    }
+
+    #[test]
+    fn candidates_need_the_name_and_the_rssi() {
+        let rssi = RssiFilter {
+            min: Some(-70),
+            required: true,
+        };
+        let candidate = |advertised, signal| {
+            is_candidate("Ornament", NameMatch::Exact, rssi, advertised, signal)
+        };
+        assert!(candidate(Some("Ornament"), Some(-60)));
+        assert!(!candidate(Some("Ornament"), Some(-80)));
+        assert!(!candidate(Some("Ornament"), None));
+        assert!(!candidate(Some("Other"), Some(-60)));
+        assert!(!candidate(None, Some(-60)));
+    }
+
+    #[test]
+    fn writes_prefer_with_response() {
+        let cases = [
+            (CharPropFlags::WRITE, WriteType::WithResponse),
+            (
+                CharPropFlags::WRITE | CharPropFlags::WRITE_WITHOUT_RESPONSE,
+                WriteType::WithResponse,
+            ),
+            (
+                CharPropFlags::WRITE_WITHOUT_RESPONSE,
+                WriteType::WithoutResponse,
+            ),
+            // Let the write fail on its own, rather than guessing
+            (CharPropFlags::READ, WriteType::WithResponse),
+        ];
+        for (properties, expected) in cases {
+            let c = characteristic(properties);
+            assert_eq!(write_type(&c), expected, "{:?}", properties);
+        }
+    }
+}
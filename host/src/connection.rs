@@ -0,0 +1,363 @@
+//! Keeping a connection to the christmas ornament. The ornament can drop off at
+//! any time, so everything that talks to it goes through a `SharedConnection`.
+//! This is empty while we're reconnecting.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Error, Result};
+use btleplug::api::{Peripheral as _, Service};
+use btleplug::platform::Peripheral;
+use tokio::sync::{watch, Mutex as AsyncMutex};
+use tokio::time::Instant;
+
+use crate::ble;
+use crate::ble::{AdapterStatus, CharUuid};
+
+/// How often to check whether the BLE adapter is back, while it's unavailable.
+const ADAPTER_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Everything we get from connecting to the ornament. The `service` is the
+/// ornament's own, and the `standard` ones are whatever standard services it
+/// has. See `ble::get_standard_services`.
+#[derive(Clone)]
+pub struct Connection {
+    pub peripheral: Peripheral,
+    pub service: Service,
+    pub standard: Vec<Service>,
+}
+
+/// The connection to the ornament, shared between all the tasks that use it.
+/// It's `None` while we're reconnecting. Tasks that have to redo work when the
+/// connection changes can `subscribe` to it.
+#[derive(Clone)]
+pub struct SharedConnection {
+    current: Arc<watch::Sender<Option<Connection>>>,
+    /// When the next attempt to reconnect starts, if we're waiting for one.
+    next_attempt: Arc<Mutex<Option<Instant>>>,
+    /// Held while connecting, so only one task does it at a time.
+    changing: Arc<AsyncMutex<()>>,
+    /// How many times we've connected since the first time.
+    reconnects: Arc<AtomicU64>,
+}
+
+impl SharedConnection {
+    pub fn new(connection: Connection) -> Self {
+        SharedConnection {
+            current: Arc::new(watch::Sender::new(Some(connection))),
+            next_attempt: Default::default(),
+            changing: Default::default(),
+            reconnects: Default::default(),
+        }
+    }
+
+    /// Get the current connection, if we have one.
+    pub fn get(&self) -> Option<Connection> {
+        self.current.borrow().clone()
+    }
+
+    /// Replace the current connection, notifying everyone subscribed.
+    pub fn set(&self, connection: Option<Connection>) {
+        self.current.send_replace(connection);
+    }
+
+    /// Watch for the connection changing.
+    pub fn subscribe(&self) -> watch::Receiver<Option<Connection>> {
+        self.current.subscribe()
+    }
+
+    /// How long a client should wait before trying again, if we're
+    /// reconnecting. This is until the next attempt starts, or a second if one
+    /// is already running. Returns `None` if we're connected.
+    pub fn retry_after(&self) -> Option<Duration> {
+        if self.current.borrow().is_some() {
+            return None;
+        }
+        let until_next = match *self.next_attempt.lock().unwrap() {
+            Some(t) => t.saturating_duration_since(Instant::now()),
+            None => Duration::ZERO,
+        };
+        Some(until_next.max(Duration::from_secs(1)))
+    }
+
+    /// How many times we've connected again, after the connection we started
+    /// with.
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    /// Replace the connection after connecting again, and count it.
+    fn reconnected(&self, connection: Connection) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+        self.set(Some(connection));
+    }
+}
+
+/// How long to wait between attempts to reconnect. The delay starts at `base`
+/// and doubles after every failed attempt, up to `cap`. We give up after
+/// `max_attempts`.
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    pub base: Duration,
+    pub cap: Duration,
+    pub max_attempts: u32,
+}
+
+impl Backoff {
+    /// The delay before the given `attempt`, counting from zero. On top of the
+    /// exponential delay, we add up to half of it again at random. That way,
+    /// several hosts that lost their ornaments at the same time don't all retry
+    /// at the same time.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt).unwrap_or(u32::MAX);
+        let delay = self.base.saturating_mul(factor).min(self.cap);
+        delay + delay.mul_f64(rand::random::<f64>() / 2.0)
+    }
+
+    /// Keep trying `attempts` until one succeeds, waiting out the `delay` before
+    /// each. While we wait, `next_attempt` says when the next one starts. Fails
+    /// after `max_attempts` failures.
+    ///
+    /// We wait for the adapter before each attempt. If it's gone again after
+    /// one fails, that failure doesn't count, since it wasn't the ornament's
+    /// fault.
+    async fn retry<A: Attempts>(
+        &self,
+        attempts: &A,
+        next_attempt: &Mutex<Option<Instant>>,
+    ) -> Result<A::Output> {
+        let mut attempt = 0;
+        while attempt < self.max_attempts {
+            attempts.wait_for_adapter().await;
+            let delay = self.delay(attempt);
+            log::info!(
+                "Reconnecting in {:.1?} (attempt {} of {})",
+                delay,
+                attempt + 1,
+                self.max_attempts
+            );
+            *next_attempt.lock().unwrap() = Some(Instant::now() + delay);
+            tokio::time::sleep(delay).await;
+            *next_attempt.lock().unwrap() = None;
+            match attempts.attempt().await {
+                Ok(o) => return Ok(o),
+                Err(e) => log::warn!("Could not reconnect: {:?}", e),
+            }
+            if attempts.adapter_available().await {
+                attempt += 1;
+            }
+        }
+        anyhow::bail!("Could not reconnect after {} attempts", self.max_attempts);
+    }
+}
+
+/// What `Backoff::retry` retries, and how it checks on the adapter in between.
+trait Attempts {
+    type Output;
+
+    async fn wait_for_adapter(&self);
+    async fn adapter_available(&self) -> bool;
+    async fn attempt(&self) -> Result<Self::Output>;
+}
+
+/// Everything needed to find and connect to the ornament, both at startup and
+/// after it drops off.
+pub struct Connector {
+    /// The index of the BLE adapter to use. See `ble::adapters`.
+    pub adapter: usize,
+    pub name: String,
+    pub name_match: ble::NameMatch,
+    /// Whether to fail if more than one peripheral matches. See `ble::connect`.
+    pub strict: bool,
+    /// Which peripherals are close enough to connect to.
+    pub rssi: ble::RssiFilter,
+    pub scan_duration: Duration,
+    /// How long a single attempt to connect can take, including scanning.
+    pub timeout: Duration,
+    /// What to read right after connecting, if anything. See `ble::connect`.
+    pub warmup: Option<CharUuid>,
+    pub backoff: Backoff,
+}
+
+impl Connector {
+    /// Make one attempt to connect to the ornament.
+    pub async fn connect(&self) -> Result<Connection> {
+        let connect = async {
+            let peripheral = ble::connect(
+                self.adapter,
+                &self.name,
+                self.name_match,
+                self.strict,
+                self.rssi,
+                self.scan_duration,
+                self.warmup,
+            )
+            .await?;
+            let service = ble::get_service(&peripheral)?;
+            let standard = ble::get_standard_services(&peripheral);
+            Ok::<_, Error>(Connection {
+                peripheral,
+                service,
+                standard,
+            })
+        };
+        tokio::time::timeout(self.timeout, connect)
+            .await
+            .context("Timed out connecting to the christmas ornament")?
+    }
+
+    /// Wait until our BLE adapter is available, checking every
+    /// `ADAPTER_POLL_INTERVAL`. Returns right away if it already is.
+    pub async fn wait_for_adapter(&self) {
+        let mut status = ble::adapter_status(self.adapter).await;
+        if status == AdapterStatus::Available {
+            return;
+        }
+        log::warn!(
+            "Bluetooth adapter {} is {}, so waiting for it to come back",
+            self.adapter,
+            status
+        );
+        while status != AdapterStatus::Available {
+            tokio::time::sleep(ADAPTER_POLL_INTERVAL).await;
+            let next = ble::adapter_status(self.adapter).await;
+            if next != status {
+                log::info!("Bluetooth adapter {} is now {}", self.adapter, next);
+            }
+            status = next;
+        }
+    }
+
+    /// Reconnect to the ornament after it dropped off, backing off between
+    /// attempts. The `connection` is empty until we succeed. Every call starts
+    /// over from the base delay. Fails if we run out of attempts.
+    ///
+    /// If our adapter is unavailable, like when it's powered off, we wait for
+    /// it to come back first. Attempts that fail because it went away don't
+    /// count, so we don't give up while the host is in airplane mode.
+    pub async fn reconnect(&self, connection: &SharedConnection) -> Result<()> {
+        let _changing = connection.changing.lock().await;
+        // Someone else might have reconnected while we were waiting
+        if let Some(c) = connection.get() {
+            if c.peripheral.is_connected().await.unwrap_or(false) {
+                return Ok(());
+            }
+        }
+
+        connection.set(None);
+        let c = self.backoff.retry(self, &connection.next_attempt).await?;
+        log::info!("Reconnected to the christmas ornament");
+        connection.reconnected(c);
+        Ok(())
+    }
+
+    /// Drop the current `connection`, if any, and connect again from scratch.
+    /// This is for when the ornament moved, so it scans again. Unlike
+    /// `reconnect`, this only makes one attempt. On failure, the `connection`
+    /// is left empty for `reconnect` to deal with.
+    pub async fn rescan(&self, connection: &SharedConnection) -> Result<Connection> {
+        let _changing = connection.changing.lock().await;
+        if let Some(old) = connection.get() {
+            connection.set(None);
+            if let Err(e) = old.peripheral.disconnect().await {
+                log::warn!("Could not disconnect from the christmas ornament: {:?}", e);
+            }
+        }
+
+        log::info!("Reconnecting to the christmas ornament on request");
+        let c = self.connect().await?;
+        connection.reconnected(c.clone());
+        Ok(c)
+    }
+}
+
+impl Attempts for Connector {
+    type Output = Connection;
+
+    async fn wait_for_adapter(&self) {
+        Connector::wait_for_adapter(self).await
+    }
+
+    async fn adapter_available(&self) -> bool {
+        ble::adapter_status(self.adapter).await == AdapterStatus::Available
+    }
+
+    async fn attempt(&self) -> Result<Connection> {
+        self.connect().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// Attempts that always fail. The adapter is there after each one according
+    /// to `available`, and is there from then on.
+    struct Failing {
+        available: Mutex<VecDeque<bool>>,
+        attempts: Mutex<u32>,
+    }
+
+    impl Failing {
+        fn new(available: &[bool]) -> Self {
+            Failing {
+                available: Mutex::new(available.iter().copied().collect()),
+                attempts: Mutex::new(0),
+            }
+        }
+    }
+
+    impl Attempts for Failing {
+        type Output = ();
+
+        async fn wait_for_adapter(&self) {}
+
+        async fn adapter_available(&self) -> bool {
+            self.available.lock().unwrap().pop_front().unwrap_or(true)
+        }
+
+        async fn attempt(&self) -> Result<()> {
+            *self.attempts.lock().unwrap() += 1;
+            anyhow::bail!("no ornament")
+        }
+    }
+
+    fn backoff(max_attempts: u32) -> Backoff {
+        Backoff {
+            base: Duration::ZERO,
+            cap: Duration::ZERO,
+            max_attempts,
+        }
+    }
+
+    #[tokio::test]
+    async fn failures_without_the_adapter_dont_count() {
+        let next_attempt = Mutex::new(None);
+
+        let failing = Failing::new(&[]);
+        assert!(backoff(3).retry(&failing, &next_attempt).await.is_err());
+        assert_eq!(*failing.attempts.lock().unwrap(), 3);
+
+        let failing = Failing::new(&[true, false, false, true]);
+        assert!(backoff(3).retry(&failing, &next_attempt).await.is_err());
+        assert_eq!(*failing.attempts.lock().unwrap(), 5);
+        assert_eq!(*next_attempt.lock().unwrap(), None);
+    }
+
+    #[test]
+    fn delays_double_up_to_the_cap() {
+        let backoff = Backoff {
+            base: Duration::from_secs(1),
+            cap: Duration::from_secs(5),
+            max_attempts: 10,
+        };
+        for (attempt, expected) in [(0, 1), (1, 2), (2, 4), (3, 5), (40, 5)] {
+            let expected = Duration::from_secs(expected);
+            let delay = backoff.delay(attempt);
+            assert!(expected <= delay && delay <= expected.mul_f64(1.5));
+        }
+    }
+}
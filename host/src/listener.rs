@@ -0,0 +1,48 @@
+//! Binding the socket the HTTP server listens on.
+//!
+//! Binding to `::` is meant to accept both IPv6 clients and IPv4 clients, the
+//! latter as IPv4-mapped addresses. Whether it does by default depends on the
+//! platform. Linux follows the `net.ipv6.bindv6only` sysctl, which is usually
+//! off but not always. Windows and the BSDs only accept IPv6 by default, and
+//! OpenBSD can't do dual-stack at all. So, we turn `IPV6_V6ONLY` off ourselves
+//! rather than rely on the default.
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::TcpListener;
+
+/// How many connections can wait to be accepted. This is what the standard
+/// library uses.
+const BACKLOG: i32 = 128;
+
+/// Listen on `address` and `port`. If `address` is `::`, the listener is
+/// dual-stack where the platform supports it. If it doesn't, we log that and
+/// only accept IPv6. Every other address is bound as usual.
+pub async fn bind(address: IpAddr, port: u16) -> Result<TcpListener> {
+    let addr = SocketAddr::new(address, port);
+    if address != IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        return TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Could not listen on {}", addr));
+    }
+
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))
+        .context("Could not create an IPv6 socket")?;
+    if let Err(e) = socket.set_only_v6(false) {
+        log::warn!(
+            "Only accepting IPv6 clients, since dual-stack isn't supported: {}",
+            e
+        );
+    }
+    // Tokio does this too, so restarting doesn't have to wait out TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&addr.into())
+        .with_context(|| format!("Could not listen on {}", addr))?;
+    socket.listen(BACKLOG)?;
+    TcpListener::from_std(socket.into()).context("Could not hand the listener to tokio")
+}
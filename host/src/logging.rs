@@ -0,0 +1,52 @@
+//! Setting up the logger. By default, logs are human-readable text. They can
+//! also be newline-delimited JSON, for log aggregators. Either way, the level is
+//! set with `RUST_LOG`.
+//!
+//! Log calls can attach structured fields, like `log::info!(uuid:% = u; ...)`.
+//! These become their own keys in JSON records. Text records only have the
+//! message, so it should say everything on its own.
+
+use std::io::Write;
+
+use log::kv::{Error, Key, Value, VisitSource};
+use serde_json::{Map, Number};
+
+/// Start logging, as JSON if `json` is set and as text otherwise.
+pub fn init(json: bool) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if json {
+        builder.format(|buf, record| {
+            let mut fields = Map::new();
+            fields.insert("timestamp".into(), buf.timestamp().to_string().into());
+            fields.insert("level".into(), record.level().as_str().into());
+            fields.insert("target".into(), record.target().into());
+            fields.insert("message".into(), record.args().to_string().into());
+            // Failing to collect a field just means it's left out
+            let _ = record.key_values().visit(&mut Fields(&mut fields));
+            writeln!(buf, "{}", serde_json::Value::Object(fields))
+        });
+    }
+    builder.init();
+}
+
+/// Collects a record's structured fields into a JSON object. Numbers and
+/// booleans stay as they are, and everything else is formatted as a string.
+struct Fields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        let value = if let Some(n) = value.to_u64() {
+            n.into()
+        } else if let Some(n) = value.to_i64() {
+            n.into()
+        } else if let Some(n) = value.to_f64().and_then(Number::from_f64) {
+            n.into()
+        } else if let Some(b) = value.to_bool() {
+            b.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
@@ -1,33 +1,104 @@
 mod attrs;
 mod ble;
+mod connection;
+mod listener;
+mod logging;
+mod mdns;
+mod selftest;
+mod tasks;
+mod transport;
 
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{Context, Error, Result};
 use argparse::ArgumentParser;
-use btleplug::api::Peripheral as _;
+use axum::http::{Method, StatusCode};
+use axum::Router;
+use btleplug::api::{Central as _, Peripheral as _, Service};
 use btleplug::platform::Peripheral;
-use tokio::net::TcpListener;
-use tokio::task::JoinSet;
+use futures::StreamExt;
+use tokio_util::sync::CancellationToken;
+use tower_http::timeout::TimeoutLayer;
 
 use attrs::ApplicationState;
+use connection::{Backoff, Connection, Connector, SharedConnection};
+use tasks::{Task, Tasks};
+use transport::BleTransport;
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::init();
-
-    let mut local_name = String::from("Christmas Ornament");
+async fn main() -> Result<ExitCode> {
+    // Start the clock for `/host/status` right away, so its uptime includes
+    // connecting
+    let metrics = attrs::HostMetrics::default();
+    let mut local_name: Option<String> = None;
+    let mut adapter = 0usize;
+    let mut list_adapters = false;
+    let mut exact_name = false;
+    let mut name_prefix = false;
+    let mut strict_match = false;
+    let mut min_rssi: Option<i16> = None;
+    let mut require_rssi = false;
+    let mut warmup = true;
     let mut scan_time_s = 15u64;
+    let mut connect_timeout_s = 60u64;
     let mut disconnect_poll_s = 1u64;
+    let mut disconnect_jitter_pct = 10u64;
+    let mut bootcount_poll_s = 5u64;
+    let mut reconnect_base_s = 1u64;
+    let mut reconnect_cap_s = 60u64;
+    let mut reconnect_attempts = 10u32;
+    let mut bind = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
     let mut port = 3000u16;
+    let mut request_timeout_s = 30u64;
+    let mut refresh_sensors = false;
+    let mut battery_interval_s = 60u64;
+    let mut battery_history = 60usize;
+    let mut light_average = 1usize;
+    let mut calibration_file: Option<PathBuf> = None;
+    let mut mdns = false;
+    let mut debug_routes = false;
+    let mut read_only = false;
+    let mut raw_commands = false;
+    let mut once: Option<String> = None;
+    let mut raw = false;
+    let mut self_test = false;
+    let mut self_test_write = false;
+    let mut json_logs = false;
+    let mut lenient_length = false;
+    let mut axis_thresholds = false;
+    let mut require_characteristics = false;
     {
         let mut ap = ArgumentParser::new();
         ap.set_description("Interface with the Christmas ornament over BLE");
+        ap.refer(&mut adapter).metavar("ADAPTER").add_option(
+            &["--adapter"],
+            argparse::Store,
+            "Index of the bluetooth adapter to use. See --list-adapters",
+        );
+        ap.refer(&mut list_adapters).add_option(
+            &["--list-adapters"],
+            argparse::StoreTrue,
+            "Print the index and description of every bluetooth adapter, and \
+             exit",
+        );
         ap.refer(&mut scan_time_s).metavar("SCAN_TIME").add_option(
             &["-t", "--scan-time"],
             argparse::Store,
             "Time to scan for peripherals for, in seconds",
         );
+        ap.refer(&mut connect_timeout_s)
+            .metavar("CONNECT_TIMEOUT")
+            .add_option(
+                &["--connect-timeout"],
+                argparse::Store,
+                "Time to spend finding and connecting to the ornament in total, \
+                 including scanning, in seconds",
+            );
         ap.refer(&mut disconnect_poll_s)
             .metavar("DISCONNECT_POLL_INTERVAL")
             .add_option(
@@ -35,62 +106,630 @@ async fn main() -> Result<()> {
                 argparse::Store,
                 "Time to poll for the ornament disconnecting, in seconds",
             );
+        ap.refer(&mut disconnect_jitter_pct)
+            .metavar("DISCONNECT_POLL_JITTER")
+            .add_option(
+                &["--disconnect-jitter"],
+                argparse::Store,
+                "How far each disconnect poll may be from the interval at \
+                 random, as a percentage of it. The average stays the same",
+            );
+        ap.refer(&mut bootcount_poll_s)
+            .metavar("BOOTCOUNT_POLL_INTERVAL")
+            .add_option(
+                &["--bootcount-poll"],
+                argparse::Store,
+                "Time to poll for the boot count changing, in seconds, if the \
+                 ornament doesn't let us subscribe to it",
+            );
+        ap.refer(&mut reconnect_base_s)
+            .metavar("RECONNECT_BASE")
+            .add_option(
+                &["--reconnect-base"],
+                argparse::Store,
+                "Time to wait before the first attempt to reconnect to the \
+                 ornament, in seconds. This doubles after every failed attempt",
+            );
+        ap.refer(&mut reconnect_cap_s)
+            .metavar("RECONNECT_CAP")
+            .add_option(
+                &["--reconnect-cap"],
+                argparse::Store,
+                "Longest time to wait between attempts to reconnect, before \
+                 jitter, in seconds",
+            );
+        ap.refer(&mut reconnect_attempts)
+            .metavar("RECONNECT_ATTEMPTS")
+            .add_option(
+                &["--reconnect-attempts"],
+                argparse::Store,
+                "Number of attempts to reconnect before giving up and exiting",
+            );
+        ap.refer(&mut battery_interval_s)
+            .metavar("BATTERY_INTERVAL")
+            .add_option(
+                &["--battery-interval"],
+                argparse::Store,
+                "Time between samples of the battery voltage for \
+                 /battery/history, in seconds",
+            );
+        ap.refer(&mut battery_history)
+            .metavar("BATTERY_HISTORY")
+            .add_option(
+                &["--battery-history"],
+                argparse::Store,
+                "Number of battery samples to keep for /battery/history, or 0 \
+                 to not sample the battery at all",
+            );
+        ap.refer(&mut light_average)
+            .metavar("LIGHT_AVERAGE")
+            .add_option(
+                &["--light-average"],
+                argparse::Store,
+                "Number of light readings to average over for /light. The \
+                 default of 1 doesn't smooth at all",
+            );
+        ap.refer(&mut calibration_file)
+            .metavar("CALIBRATION_FILE")
+            .add_option(
+                &["--calibration-file"],
+                argparse::StoreOption,
+                "File to keep sensor calibrations in, so they survive restarts. \
+                 Without it, calibrations only last until we exit",
+            );
+        ap.refer(&mut refresh_sensors).add_option(
+            &["--refresh-sensors"],
+            argparse::StoreTrue,
+            "Refresh the cached sensor readings every time we poll for the \
+             ornament disconnecting",
+        );
+        ap.refer(&mut bind).metavar("ADDRESS").add_option(
+            &["--bind"],
+            argparse::Store,
+            "Address to listen on for HTTP requests. Use `::` for both IPv4 and \
+             IPv6",
+        );
         ap.refer(&mut port).metavar("PORT").add_option(
             &["-p", "--port"],
             argparse::Store,
             "Port to listen on for HTTP requests",
         );
+        ap.refer(&mut request_timeout_s)
+            .metavar("REQUEST_TIMEOUT")
+            .add_option(
+                &["--request-timeout"],
+                argparse::Store,
+                "Time to spend handling an HTTP request before giving up on it, \
+                 in seconds. This doesn't apply to streaming endpoints",
+            );
+        ap.refer(&mut exact_name).add_option(
+            &["--exact-name"],
+            argparse::StoreTrue,
+            "Only match peripherals whose name is exactly LOCAL_NAME, instead of \
+             ignoring case and surrounding whitespace",
+        );
+        ap.refer(&mut name_prefix).add_option(
+            &["--name-prefix"],
+            argparse::StoreTrue,
+            "Match any peripheral whose name starts with LOCAL_NAME, instead of \
+             the whole name",
+        );
+        ap.refer(&mut min_rssi).metavar("DBM").add_option(
+            &["--min-rssi"],
+            argparse::StoreOption,
+            "Ignore peripherals with a weaker signal than this, in dBm",
+        );
+        ap.refer(&mut require_rssi).add_option(
+            &["--require-rssi"],
+            argparse::StoreTrue,
+            "Ignore peripherals we don't know the signal strength of, instead of \
+             letting them through --min-rssi",
+        );
+        ap.refer(&mut strict_match).add_option(
+            &["--strict-match"],
+            argparse::StoreTrue,
+            "Fail if more than one peripheral matches LOCAL_NAME, instead of \
+             picking the one with the strongest signal. This always scans for \
+             the whole --scan-time, so every match has a chance to show up",
+        );
+        ap.refer(&mut warmup)
+            .add_option(
+                &["--warmup"],
+                argparse::StoreTrue,
+                "Read the boot count once right after connecting, so the first \
+                 request doesn't pay for a cold connection. This is the default",
+            )
+            .add_option(
+                &["--no-warmup"],
+                argparse::StoreFalse,
+                "Don't read anything right after connecting",
+            );
+        ap.refer(&mut mdns).add_option(
+            &["--mdns"],
+            argparse::StoreTrue,
+            "Advertise the HTTP server over mDNS",
+        );
+        ap.refer(&mut debug_routes).add_option(
+            &["--debug-routes"],
+            argparse::StoreTrue,
+            "Serve the routes under /debug, which expose what the ornament \
+             actually has for bringing up firmware",
+        );
+        ap.refer(&mut read_only).add_option(
+            &["--read-only"],
+            argparse::StoreTrue,
+            "Don't serve any of the routes that change anything on the ornament \
+             or the host, like POST and DELETE",
+        );
+        ap.refer(&mut raw_commands).add_option(
+            &["--raw-commands"],
+            argparse::StoreTrue,
+            "Let POST /command/NAME take a numeric opcode as the NAME, for \
+             commands that don't have a name yet",
+        );
+        ap.refer(&mut once).metavar("ATTRIBUTE").add_option(
+            &["--once"],
+            argparse::StoreOption,
+            "Read ATTRIBUTE, print it, and exit without starting the server. \
+             Attributes are named by their path, like `battery` or \
+             `light_threshold`",
+        );
+        ap.refer(&mut raw).add_option(
+            &["--raw"],
+            argparse::StoreTrue,
+            "With --once, print only the value instead of the whole JSON object",
+        );
+        ap.refer(&mut self_test).add_option(
+            &["--self-test"],
+            argparse::StoreTrue,
+            "Read every attribute, print whether each worked, and exit without \
+             starting the server. Exits with 1 if any of them failed",
+        );
+        ap.refer(&mut self_test_write).add_option(
+            &["--self-test-write"],
+            argparse::StoreTrue,
+            "With --self-test, also write a value one step away from each \
+             writable attribute's current value, check that it reads back, and \
+             restore the original",
+        );
+        ap.refer(&mut json_logs).add_option(
+            &["--json-logs"],
+            argparse::StoreTrue,
+            "Log newline-delimited JSON records instead of text",
+        );
+        ap.refer(&mut lenient_length).add_option(
+            &["--lenient-length"],
+            argparse::StoreTrue,
+            "Accept characteristics that are longer than expected, ignoring \
+             the extra bytes",
+        );
+        ap.refer(&mut axis_thresholds).add_option(
+            &["--axis-thresholds"],
+            argparse::StoreTrue,
+            "The ornament's firmware supports per-axis accelerometer \
+             thresholds, in the top bits of the threshold characteristic",
+        );
+        ap.refer(&mut require_characteristics).add_option(
+            &["--require-characteristics"],
+            argparse::StoreTrue,
+            "Fail at startup if the ornament has none of the characteristics we \
+             expect",
+        );
         ap.refer(&mut local_name)
             .metavar("LOCAL_NAME")
-            .required()
             .add_argument(
                 "local_name",
-                argparse::Store,
-                "Display name of the ornament",
+                argparse::StoreOption,
+                "Display name of the ornament. Required unless --list-adapters \
+                 is given",
             );
         ap.parse_args_or_exit();
     }
+    logging::init(json_logs);
+
+    if list_adapters {
+        return print_adapters().await;
+    }
+    // Everything past here connects to the ornament, so it needs its name
+    let Some(local_name) = local_name else {
+        anyhow::bail!("LOCAL_NAME is required, unless --list-adapters is given");
+    };
 
-    let scan_duration = Duration::from_secs(scan_time_s);
     let poll_duration = Duration::from_secs(disconnect_poll_s);
+    if disconnect_jitter_pct > 100 {
+        anyhow::bail!("--disconnect-jitter can be at most 100");
+    }
+    let poll_jitter = disconnect_jitter_pct as f64 / 100.0;
+    if self_test_write && read_only {
+        anyhow::bail!("--self-test-write can't be used with --read-only");
+    }
+    let bootcount_poll_duration = Duration::from_secs(bootcount_poll_s);
 
-    let peripheral = ble::connect(&local_name, scan_duration).await?;
-    let service = ble::get_service(&peripheral)?;
+    // Load these before connecting, so a bad file fails fast
+    let calibrations = match calibration_file {
+        Some(f) => attrs::Calibrations::load(f)?,
+        None => Default::default(),
+    };
 
-    let app = attrs::router().with_state(ApplicationState {
-        peripheral: peripheral.clone(),
-        service: service.clone(),
+    let connector = Arc::new(Connector {
+        adapter,
+        name: local_name.clone(),
+        name_match: match (exact_name, name_prefix) {
+            (false, false) => ble::NameMatch::Loose,
+            (true, false) => ble::NameMatch::Exact,
+            (false, true) => ble::NameMatch::Prefix,
+            (true, true) => {
+                anyhow::bail!("Only one of --exact-name and --name-prefix can be given")
+            }
+        },
+        strict: strict_match,
+        rssi: ble::RssiFilter {
+            min: min_rssi,
+            required: require_rssi,
+        },
+        scan_duration: Duration::from_secs(scan_time_s),
+        timeout: Duration::from_secs(connect_timeout_s),
+        warmup: warmup.then_some(attrs::BOOTCOUNT_UUID),
+        backoff: Backoff {
+            base: Duration::from_secs(reconnect_base_s),
+            cap: Duration::from_secs(reconnect_cap_s),
+            max_attempts: reconnect_attempts,
+        },
     });
 
-    let listener = TcpListener::bind(("0.0.0.0", port)).await.unwrap();
+    // Let the user abort the initial connection. We don't retry this one, since
+    // it's more likely that the ornament is just off.
+    let connection = tokio::select! {
+        r = connector.connect() => r?,
+        _ = tokio::signal::ctrl_c() => anyhow::bail!("Interrupted while connecting"),
+    };
 
-    let mut joinset = JoinSet::new();
-    joinset.spawn(async { axum::serve(listener, app).await.context("Server died") });
-    joinset.spawn(disconnect_handler(peripheral.clone(), poll_duration));
+    // Catch firmware that doesn't have any of the characteristics we want
+    if ble::check_characteristics(&connection.service, &attrs::expected_uuids()) == 0 {
+        if require_characteristics {
+            anyhow::bail!(
+                "The christmas ornament's service has none of the expected characteristics"
+            );
+        }
+        log::error!(
+            "The christmas ornament's service has none of the expected characteristics, so \
+             every request will fail"
+        );
+    }
 
-    while let Some(r) = joinset.join_next().await {
-        let r = match r {
-            Ok(r) => r,
-            Err(e) => anyhow::bail!("{:?}", e),
-        };
-        match r {
-            Ok(()) => anyhow::bail!("Some task finished when it was supposed to run forever"),
-            Err(e) => anyhow::bail!("{:?}", e),
+    // Cancelled when we get Ctrl-C. Tasks and responses that need to clean up
+    // watch for this themselves. The rest are just stopped.
+    let shutdown = CancellationToken::new();
+
+    let connection = SharedConnection::new(connection);
+    let state = ApplicationState {
+        transport: Arc::new(BleTransport::new(connection.clone(), connector.clone())),
+        cache: Default::default(),
+        bootcount: Default::default(),
+        battery_history: attrs::BatteryHistory::new(battery_history),
+        averages: attrs::RollingAverage::new(light_average),
+        calibrations,
+        metrics,
+        raw_commands,
+        read_only,
+        lenient_length,
+        axis_thresholds,
+        request_timeout: Duration::from_secs(request_timeout_s),
+        shutdown: shutdown.clone(),
+    };
+    // The request timeout is the outer bound on handling a request. Handlers
+    // that put their own timeouts on BLE operations finish first, as long as
+    // those are shorter. Streaming routes are expected to stay open.
+    let mut router = attrs::router(read_only);
+    if debug_routes {
+        router = router.merge(attrs::debug_router());
+    }
+    let app = router
+        .layer(TimeoutLayer::with_status_code(
+            StatusCode::GATEWAY_TIMEOUT,
+            state.request_timeout,
+        ))
+        .merge(attrs::streaming_router())
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            attrs::count_request,
+        ))
+        .with_state(state.clone());
+
+    // If we're only checking the attributes or reading one of them, do that and
+    // skip everything else
+    if self_test {
+        let passed = selftest::run(app, self_test_write).await;
+        return Ok(if passed {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        });
+    }
+    if let Some(attribute) = once {
+        return read_once(app, &attribute, raw)
+            .await
+            .map(|()| ExitCode::SUCCESS);
+    }
+
+    let listener = listener::bind(bind, port).await?;
+
+    let mut tasks = Tasks::default();
+    tasks.spawn(Task::Server, {
+        let shutdown = shutdown.clone();
+        async {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown.cancelled_owned())
+                .await
+                .context("Server died")
         }
+    });
+    tasks.spawn(
+        Task::DisconnectHandler,
+        until_shutdown(
+            &shutdown,
+            disconnect_handler(
+                state.clone(),
+                connection.clone(),
+                connector.clone(),
+                poll_duration,
+                poll_jitter,
+                refresh_sensors,
+            ),
+        ),
+    );
+    if battery_history != 0 {
+        tasks.spawn(
+            Task::BatteryHandler,
+            until_shutdown(
+                &shutdown,
+                battery_handler(state.clone(), Duration::from_secs(battery_interval_s)),
+            ),
+        );
+    }
+    tasks.spawn(
+        Task::BootcountHandler,
+        until_shutdown(
+            &shutdown,
+            bootcount_handler(state.clone(), connection.clone(), bootcount_poll_duration),
+        ),
+    );
+    if mdns {
+        tasks.spawn(
+            Task::Mdns,
+            mdns::advertise(local_name.clone(), port, shutdown.clone()),
+        );
     }
 
-    // We should always pop some result, so this is unreachable
-    unreachable!();
+    tokio::select! {
+        // None of the tasks should ever finish, so the first one to return is
+        // an error regardless of its result
+        r = tasks.join_next() => {
+            let (task, r) = r.expect("We should always pop some result");
+            match r {
+                Ok(Ok(())) => log::error!("The {} finished when it was supposed to run forever", task),
+                Ok(Err(e)) => log::error!("The {} failed: {:?}", task, e),
+                Err(e) => log::error!("The {} panicked: {:?}", task, e),
+            }
+
+            // Stop everything else, and try not to leave the ornament connected
+            tasks.abort_all().await;
+            if let Some(c) = connection.get() {
+                if let Err(e) = c.peripheral.disconnect().await {
+                    log::warn!("Could not disconnect from the christmas ornament: {:?}", e);
+                }
+            }
+            Ok(ExitCode::from(task.exit_code()))
+        }
+        // On Ctrl-C, tell all the tasks to stop, and wait for them to do so
+        r = tokio::signal::ctrl_c() => {
+            r.context("Failed to listen for Ctrl-C")?;
+            log::info!("Shutting down");
+            shutdown.cancel();
+            while let Some((task, r)) = tasks.join_next().await {
+                match r {
+                    Ok(Ok(())) => (),
+                    Ok(Err(e)) => log::error!("The {} failed while shutting down: {:?}", task, e),
+                    Err(e) => log::error!("The {} failed while shutting down: {:?}", task, e),
+                }
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+    }
+}
+
+/// Print every BLE adapter to stdout, along with the index `--adapter` takes for
+/// it. Not having any is a failure, since we couldn't connect anyway.
+async fn print_adapters() -> Result<ExitCode> {
+    let adapters = ble::adapters().await?;
+    if adapters.is_empty() {
+        log::error!("No bluetooth adapters found");
+        return Ok(ExitCode::FAILURE);
+    }
+    for (i, adapter) in adapters.iter().enumerate() {
+        let info = adapter
+            .adapter_info()
+            .await
+            .unwrap_or_else(|e| format!("(no description: {})", e));
+        println!("{}: {}", i, info);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Read the `attribute` and print it to stdout. This goes through the `app`'s
+/// router, so it does exactly what a `GET` request would. If `raw` is set, only
+/// print the value instead of the whole object. Fails if the request did.
+async fn read_once(app: Router, attribute: &str, raw: bool) -> Result<()> {
+    let path = attrs::attribute_path(attribute);
+    let (status, body) = attrs::dispatch(app, Method::GET, &path, None)
+        .await
+        .context("Failed to read attribute")?;
+    if !status.is_success() {
+        anyhow::bail!("Failed to read {}: {}", attribute, status);
+    }
+
+    match body.get("value") {
+        Some(value) if raw => println!("{}", value),
+        _ => println!("{}", body),
+    }
+    Ok(())
+}
+
+/// Run the `task` until it finishes or until `shutdown` is cancelled, whichever
+/// comes first. This is for tasks that don't have to clean up.
+fn until_shutdown(
+    shutdown: &CancellationToken,
+    task: impl Future<Output = Result<(), Error>>,
+) -> impl Future<Output = Result<(), Error>> {
+    let shutdown = shutdown.clone();
+    async move {
+        tokio::select! {
+            r = task => r,
+            _ = shutdown.cancelled() => Ok(()),
+        }
+    }
 }
 
 /// What to do when the peripheral disconnects from us. We'll poll this every
-/// second, and cause an error if that happens.
-async fn disconnect_handler(peripheral: Peripheral, poll_interval: Duration) -> Result<(), Error> {
+/// `poll_interval`, and try to reconnect if that happens. We only
+/// cause an error if we can't. Since we're awake anyway, we can also
+/// `refresh_sensors` in the cache. Failing to read a sensor is not treated as a
+/// disconnect. Neither is a refresh that takes longer than a `poll_interval`.
+/// It's abandoned instead, so a stuck read can't keep us from noticing a
+/// disconnect.
+///
+/// If it was our adapter that went away, like in airplane mode, we log that
+/// instead. Reconnecting then waits for the adapter to come back.
+///
+/// Each poll is up to `jitter` of the interval early or late, at random. That
+/// way, several hosts on one machine don't all poll their ornaments at the same
+/// time. On average, we still poll every `poll_interval`.
+async fn disconnect_handler(
+    state: ApplicationState,
+    connection: SharedConnection,
+    connector: Arc<Connector>,
+    poll_interval: Duration,
+    jitter: f64,
+    refresh_sensors: bool,
+) -> Result<(), Error> {
+    let sensors = attrs::router(state.read_only).with_state(state.clone());
     loop {
-        tokio::time::sleep(poll_interval).await;
-        if peripheral.is_connected().await? {
+        let offset = jitter * (2.0 * rand::random::<f64>() - 1.0);
+        tokio::time::sleep(poll_interval.mul_f64(1.0 + offset)).await;
+        let connected = match connection.get() {
+            Some(c) => c.peripheral.is_connected().await.unwrap_or_else(|e| {
+                log::warn!(
+                    "Could not check whether the peripheral is connected: {:?}",
+                    e
+                );
+                false
+            }),
+            None => false,
+        };
+        if !connected {
+            // Every operation fails while the adapter is off, so check for that
+            // before blaming the peripheral
+            match ble::adapter_status(connector.adapter).await {
+                ble::AdapterStatus::Available => log::warn!("Peripheral disconnected"),
+                status => log::warn!(
+                    "Lost the peripheral, since bluetooth adapter {} is {}",
+                    connector.adapter,
+                    status
+                ),
+            }
+            connector.reconnect(&connection).await?;
+            continue;
+        }
+        if refresh_sensors {
+            let refresh = attrs::refresh_sensors(&state, &sensors);
+            if tokio::time::timeout(poll_interval, refresh).await.is_err() {
+                log::warn!("Gave up refreshing cached readings, since it took too long");
+            }
+        }
+    }
+}
+
+/// Sample the battery into its history every `interval`. Failing to read it is
+/// not an error.
+async fn battery_handler(state: ApplicationState, interval: Duration) -> Result<(), Error> {
+    loop {
+        attrs::sample_battery(&state).await;
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Subscribe to changes to the boot count characteristic. The firmware indicates
+/// on it whenever it changes.
+async fn subscribe_bootcount(peripheral: &Peripheral, service: &Service) -> Result<()> {
+    let characteristic = ble::find_characteristic(service, attrs::BOOTCOUNT_UUID)
+        .context("Could not find the boot count characteristic")?;
+    let subscription = ble::subscribe(peripheral, characteristic).await?;
+    log::info!("Subscribed to the boot count using {:?}", subscription);
+    Ok(())
+}
+
+/// Watch the boot count on every `connection` we get, recording it in the
+/// `state`. We have to subscribe again after reconnecting. Older firmware
+/// doesn't support subscribing to the boot count, so we fall back to reading it
+/// every `poll_interval`.
+async fn bootcount_handler(
+    state: ApplicationState,
+    connection: SharedConnection,
+    poll_interval: Duration,
+) -> Result<(), Error> {
+    let mut changes = connection.subscribe();
+    loop {
+        let current = changes.borrow_and_update().clone();
+        let watch = async {
+            if let Some(c) = current {
+                // Read it first, so we know what the next value is a change from
+                state.bootcount.poll(&state).await;
+                match subscribe_bootcount(&c.peripheral, &c.service).await {
+                    Ok(()) => {
+                        if let Err(e) = watch_bootcount(&state, &c).await {
+                            log::warn!("Stopped watching the boot count: {:?}", e);
+                        }
+                    }
+                    Err(e) => {
+                        log::warn!("Polling the boot count instead: {:?}", e);
+                        poll_bootcount(&state, poll_interval).await;
+                    }
+                }
+            }
+            // Wait for the next connection to try again
+            std::future::pending::<()>().await
+        };
+        tokio::select! {
+            _ = watch => (),
+            r = changes.changed() => r.context("Lost track of the connection")?,
+        }
+    }
+}
+
+/// What to do when the boot count changes, meaning the ornament rebooted. We
+/// record it in the `state`. This causes an error if we stop getting
+/// notifications.
+async fn watch_bootcount(state: &ApplicationState, connection: &Connection) -> Result<(), Error> {
+    let uuid = attrs::BOOTCOUNT_UUID.uuid();
+    let mut notifications = connection
+        .peripheral
+        .notifications()
+        .await
+        .context("Could not get notifications")?;
+    while let Some(n) = notifications.next().await {
+        if n.uuid != uuid {
             continue;
         }
-        anyhow::bail!("Peripheral disconnected");
+        state.bootcount.record_bytes(&n.value);
+    }
+    anyhow::bail!("Notifications stopped");
+}
+
+/// Read the boot count every `poll_interval` and record it in the `state`, for
+/// when we can't subscribe to it. This never returns.
+async fn poll_bootcount(state: &ApplicationState, poll_interval: Duration) {
+    loop {
+        tokio::time::sleep(poll_interval).await;
+        state.bootcount.poll(state).await;
     }
 }
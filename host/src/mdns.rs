@@ -0,0 +1,113 @@
+//! Advertising the HTTP server over mDNS, so clients on the LAN can find it
+//! without knowing the host's address.
+
+use anyhow::{Context, Result};
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use tokio_util::sync::CancellationToken;
+
+/// The service type we advertise the HTTP server as.
+static SERVICE_TYPE: &str = "_ornament._tcp.local.";
+
+/// Advertise the HTTP server listening on `port`, given the ornament's display
+/// `name`. The display name is the service's instance name, and is also put in
+/// the TXT record under `name`. The host name is the machine's own, so the SRV
+/// record points at this host and not at some made-up name. The advertisement
+/// is maintained until `shutdown` is cancelled, at which point it is withdrawn.
+pub async fn advertise(name: String, port: u16, shutdown: CancellationToken) -> Result<()> {
+    let daemon = ServiceDaemon::new().context("Failed to start mDNS daemon")?;
+
+    let host_name = match hostname() {
+        Some(h) => local_host_name(&h),
+        None => {
+            log::warn!("Could not get this machine's host name, so using the display name");
+            local_host_name(&name)
+        }
+    };
+
+    let properties = [("name", name.as_str())];
+    let info = ServiceInfo::new(SERVICE_TYPE, &name, &host_name, (), port, &properties[..])
+        .context("Failed to create mDNS service")?
+        .enable_addr_auto();
+    let fullname = info.get_fullname().to_string();
+    daemon
+        .register(info)
+        .context("Failed to register mDNS service")?;
+    log::info!("Advertising {} over mDNS", fullname);
+
+    shutdown.cancelled().await;
+
+    // Withdraw the advertisement, and wait for it to go out before stopping the
+    // daemon
+    log::info!("Withdrawing mDNS advertisement");
+    daemon
+        .unregister(&fullname)
+        .context("Failed to unregister mDNS service")?
+        .recv_async()
+        .await
+        .context("Failed to unregister mDNS service")?;
+    daemon
+        .shutdown()
+        .context("Failed to stop mDNS daemon")?
+        .recv_async()
+        .await
+        .context("Failed to stop mDNS daemon")?;
+    Ok(())
+}
+
+/// Get this machine's host name, if we can. The standard library doesn't have a
+/// way to, so we ask the `hostname` command, which every Unix has.
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let output = std::process::Command::new("hostname").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let host = String::from_utf8(output.stdout).ok()?;
+    Some(host.trim().to_owned()).filter(|h| !h.is_empty())
+}
+
+/// Get this machine's host name, if we can.
+#[cfg(windows)]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+/// Get this machine's host name, if we can.
+#[cfg(not(any(unix, windows)))]
+fn hostname() -> Option<String> {
+    None
+}
+
+/// Turn a `host` name into one on `.local.`. Only the first label is kept,
+/// since the machine may know itself by a name on some other domain. That can
+/// only have letters, digits, and hyphens, so everything else becomes a hyphen.
+fn local_host_name(host: &str) -> String {
+    let label = host
+        .trim()
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect::<String>()
+        .to_lowercase();
+    format!("{}.local.", label)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_names_are_on_local() {
+        assert_eq!(local_host_name("raspberrypi"), "raspberrypi.local.");
+        assert_eq!(local_host_name("Pi.lan"), "pi.local.");
+        assert_eq!(local_host_name("My Ornament"), "my-ornament.local.");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn gets_the_host_name() {
+        assert!(hostname().is_some_and(|h| !h.is_empty()));
+    }
+}
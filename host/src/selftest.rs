@@ -0,0 +1,213 @@
+//! Checking every attribute end to end, for `--self-test`. Each check goes
+//! through the router like an HTTP request would, so it covers BLE, the
+//! transport, and each attribute's encoding all at once. The report goes to
+//! stdout, so it can be pasted into a bug report.
+
+use axum::http::{Method, StatusCode};
+use axum::Router;
+use serde_json::Value;
+
+use crate::attrs;
+use crate::attrs::AttributeSpec;
+
+/// How one check went.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Pass,
+    Fail,
+    /// The check couldn't be done, like for a `standard` attribute this
+    /// firmware doesn't have.
+    Skip,
+}
+
+/// The results of all the checks.
+#[derive(Default)]
+struct Report {
+    passed: usize,
+    failed: usize,
+    skipped: usize,
+}
+
+impl Report {
+    /// Print one check's `outcome`, along with what it was and the `detail`.
+    fn record(&mut self, outcome: Outcome, check: &str, detail: &str) {
+        let label = match outcome {
+            Outcome::Pass => {
+                self.passed += 1;
+                "PASS"
+            }
+            Outcome::Fail => {
+                self.failed += 1;
+                "FAIL"
+            }
+            Outcome::Skip => {
+                self.skipped += 1;
+                "SKIP"
+            }
+        };
+        println!("{}  {}: {}", label, check, detail);
+    }
+}
+
+/// Run every check against the `app`'s router. Every readable attribute is
+/// read. If `write` is set, every writable attribute is also written and read
+/// back, then restored. Returns whether all of the checks passed, not counting
+/// ones that were skipped.
+pub async fn run(app: Router, write: bool) -> bool {
+    let mut report = Report::default();
+    for spec in attrs::ATTRIBUTES.iter() {
+        if spec.readable {
+            check_read(&app, spec, &mut report).await;
+        }
+        if spec.writable && write {
+            check_write(&app, spec, &mut report).await;
+        }
+    }
+    println!(
+        "{} passed, {} failed, {} skipped",
+        report.passed, report.failed, report.skipped
+    );
+    report.failed == 0
+}
+
+/// Describe a failed response from its `status` and `body`. Error bodies from
+/// `AttrError` say what went wrong.
+fn describe(status: StatusCode, body: &Value) -> String {
+    match body.get("error").and_then(Value::as_str) {
+        Some(error) => format!("{} {}", status, error),
+        None => status.to_string(),
+    }
+}
+
+/// Send a request to the `app`. Returns its body on success, and why it failed
+/// otherwise.
+async fn request(
+    app: &Router,
+    method: Method,
+    path: &str,
+    body: Option<Value>,
+) -> Result<Value, String> {
+    match attrs::dispatch(app.clone(), method, path, body).await {
+        Ok((status, body)) if status.is_success() => Ok(body),
+        Ok((status, body)) => Err(describe(status, &body)),
+        Err(e) => Err(format!("{:#}", e)),
+    }
+}
+
+/// Read the attribute `spec`, and pass if that worked.
+async fn check_read(app: &Router, spec: &AttributeSpec, report: &mut Report) {
+    let check = format!("GET {}", spec.path);
+    match attrs::dispatch(app.clone(), Method::GET, spec.path, None).await {
+        Ok((status, body)) if status.is_success() => {
+            report.record(Outcome::Pass, &check, &body.to_string())
+        }
+        Ok((status, body)) => {
+            let outcome = match status {
+                StatusCode::SERVICE_UNAVAILABLE if spec.standard => Outcome::Skip,
+                _ => Outcome::Fail,
+            };
+            report.record(outcome, &check, &describe(status, &body))
+        }
+        Err(e) => report.record(Outcome::Fail, &check, &format!("{:#}", e)),
+    }
+}
+
+/// Write a value to the attribute `spec` that's one step away from what it was,
+/// and check that it reads back. Then, put the original back. A step is the
+/// attribute's `scale`, so this barely changes how the ornament behaves, even
+/// if restoring fails.
+///
+/// If we can't read the original, we skip this rather than risk leaving the
+/// test value behind. That includes when it isn't set yet, and the read check
+/// reports the rest.
+async fn check_write(app: &Router, spec: &AttributeSpec, report: &mut Report) {
+    let check = format!("POST {}", spec.path);
+    let scale = spec.scale.unwrap();
+
+    let original = match request(app, Method::GET, spec.path, None).await {
+        Ok(o) => o,
+        Err(e) => return report.record(Outcome::Skip, &check, &e),
+    };
+    let Some(value) = original["value"].as_f64() else {
+        let detail = format!("no value in {}", original);
+        return report.record(Outcome::Fail, &check, &detail);
+    };
+    let mut test = original.clone();
+    test["value"] = if value >= scale {
+        value - scale
+    } else {
+        value + scale
+    }
+    .into();
+
+    let path = format!("{}?verify=true", spec.path);
+    let written = request(app, Method::POST, &path, Some(test.clone())).await;
+    let restored = request(app, Method::POST, spec.path, Some(original)).await;
+    match (written, restored) {
+        (Ok(_), Ok(_)) => report.record(Outcome::Pass, &check, &test.to_string()),
+        (Err(e), Ok(_)) => report.record(Outcome::Fail, &check, &e),
+        (_, Err(e)) => {
+            let detail = format!("could not restore the original value: {}", e);
+            report.record(Outcome::Fail, &check, &detail)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::attrs::{testing, ATTRIBUTES};
+    use crate::transport::MockTransport;
+
+    /// A mock ornament where every attribute is set, to zero.
+    fn ornament() -> std::sync::Arc<MockTransport> {
+        let ornament = testing::ornament();
+        for spec in ATTRIBUTES.iter() {
+            ornament.set(spec.uuid, &vec![0; spec.length]);
+        }
+        ornament
+    }
+
+    #[test]
+    fn describe_includes_the_error() {
+        let body = json!({"error": "characteristic 0x0010 is not set"});
+        assert_eq!(
+            describe(StatusCode::SERVICE_UNAVAILABLE, &body),
+            "503 Service Unavailable characteristic 0x0010 is not set"
+        );
+        assert_eq!(describe(StatusCode::NOT_FOUND, &json!({})), "404 Not Found");
+    }
+
+    #[tokio::test]
+    async fn passes_when_every_attribute_works() {
+        let ornament = ornament();
+        let app = testing::app(testing::state(ornament.clone()));
+        assert!(run(app.clone(), false).await);
+        assert!(run(app, true).await);
+
+        // Everything written was put back
+        for spec in ATTRIBUTES.iter() {
+            assert_eq!(
+                ornament.value(spec.uuid).unwrap(),
+                vec![0; spec.length],
+                "{}",
+                spec.path
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_on_a_bad_read_but_not_a_missing_standard_one() {
+        let ornament = ornament();
+        let app = testing::app(testing::state(ornament.clone()));
+        let standard = ATTRIBUTES.iter().find(|a| a.standard).unwrap();
+        ornament.remove(standard.uuid);
+        ornament.set(attrs::BOOTCOUNT_UUID, &[]);
+        assert!(!run(app.clone(), false).await);
+
+        ornament.set(attrs::BOOTCOUNT_UUID, &[0]);
+        assert!(run(app, false).await);
+    }
+}
@@ -0,0 +1,112 @@
+//! Keeping track of the long-running tasks in `main`, so that when one of them
+//! stops we know which one it was.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+
+use anyhow::Result;
+use tokio::task::{self, JoinError, JoinSet};
+
+/// The tasks we run alongside the server. None of them should ever finish on
+/// their own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Task {
+    Server,
+    DisconnectHandler,
+    BatteryHandler,
+    BootcountHandler,
+    Mdns,
+}
+
+impl Task {
+    /// What the process should exit with if this task fails, so scripts can
+    /// tell which one it was. Every task has its own:
+    ///
+    /// - `2`: the HTTP server
+    /// - `3`: the disconnect handler
+    /// - `4`: the mDNS advertisement
+    /// - `5`: the battery handler
+    /// - `6`: the boot count handler
+    ///
+    /// Failures before the tasks are started exit with `1`.
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Task::Server => 2,
+            Task::DisconnectHandler => 3,
+            Task::Mdns => 4,
+            Task::BatteryHandler => 5,
+            Task::BootcountHandler => 6,
+        }
+    }
+}
+
+impl fmt::Display for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Task::Server => "HTTP server",
+            Task::DisconnectHandler => "disconnect handler",
+            Task::BatteryHandler => "battery handler",
+            Task::BootcountHandler => "boot count handler",
+            Task::Mdns => "mDNS advertisement",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A `JoinSet` that remembers which `Task` each of its futures is.
+#[derive(Default)]
+pub struct Tasks {
+    set: JoinSet<Result<()>>,
+    names: HashMap<task::Id, Task>,
+}
+
+impl Tasks {
+    /// Start running the `task`.
+    pub fn spawn(&mut self, task: Task, future: impl Future<Output = Result<()>> + Send + 'static) {
+        let handle = self.set.spawn(future);
+        self.names.insert(handle.id(), task);
+    }
+
+    /// Wait for the next task to finish, and return which one it was along with
+    /// its result. The outer result is `Err` if it panicked or was aborted.
+    /// Returns `None` if there are no tasks left.
+    pub async fn join_next(&mut self) -> Option<(Task, Result<Result<()>, JoinError>)> {
+        let (id, result) = match self.set.join_next_with_id().await? {
+            Ok((id, r)) => (id, Ok(r)),
+            Err(e) => (e.id(), Err(e)),
+        };
+        let task = self
+            .names
+            .remove(&id)
+            .expect("Every task should have a name");
+        Some((task, result))
+    }
+
+    /// Stop all the remaining tasks and wait for them to do so.
+    pub async fn abort_all(&mut self) {
+        self.set.shutdown().await;
+        self.names.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn every_task_has_its_own_exit_code() {
+        let tasks = [
+            Task::Server,
+            Task::DisconnectHandler,
+            Task::BatteryHandler,
+            Task::BootcountHandler,
+            Task::Mdns,
+        ];
+        let codes: HashSet<u8> = tasks.iter().map(|t| t.exit_code()).collect();
+        assert_eq!(codes.len(), tasks.len());
+        assert!(!codes.contains(&0) && !codes.contains(&1));
+    }
+}
@@ -0,0 +1,332 @@
+//! How the attributes get at the ornament. The attributes only ever read and
+//! write characteristics by their UUIDs, and ask whether we can reach the
+//! ornament at all, so that's all an `OrnamentTransport` has to do. The BLE one
+//! is `BleTransport`, but nothing in `crate::attrs` depends on that. Tests use
+//! `MockTransport` instead.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use btleplug::api::{CharPropFlags, Characteristic, Peripheral as _};
+use futures::future::BoxFuture;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::ble;
+use crate::ble::CharUuid;
+use crate::connection::{Connection, Connector, SharedConnection};
+
+/// Why a transport couldn't read or write a characteristic.
+#[derive(Debug)]
+pub enum TransportError {
+    /// We can't reach the ornament right now. Try again after `retry_after`.
+    NotConnected { retry_after: Duration },
+    /// The ornament doesn't have the characteristic.
+    NotFound,
+    /// Anything else.
+    Failed(anyhow::Error),
+}
+
+/// Where we are with reaching the ornament.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionState {
+    Connected,
+    /// We have a connection, but the ornament dropped off and we haven't
+    /// noticed yet.
+    Disconnected,
+    Reconnecting,
+}
+
+/// One characteristic the ornament has, whether or not we know about it. The
+/// `properties` are named as in `PROPERTY_NAMES`.
+pub struct CharacteristicInfo {
+    pub uuid: Uuid,
+    pub properties: Vec<&'static str>,
+}
+
+/// Something that can read and write the ornament's characteristics, given
+/// their UUIDs.
+///
+/// Transports also say how the connection behind them is doing. None of those
+/// methods should read anything from the ornament.
+pub trait OrnamentTransport: Send + Sync {
+    /// Read the characteristic `uuid`'s whole value, however long. Over BLE,
+    /// the OS reassembles values longer than the MTU for us.
+    fn read(&self, uuid: CharUuid) -> BoxFuture<'_, Result<Vec<u8>, TransportError>>;
+
+    fn write<'a>(
+        &'a self,
+        uuid: CharUuid,
+        value: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), TransportError>>;
+
+    /// Where we are with reaching the ornament.
+    fn state(&self) -> BoxFuture<'_, ConnectionState>;
+
+    /// How long a client should wait before trying again, if we're not
+    /// connected. Returns `None` if we are.
+    fn retry_after(&self) -> Option<Duration>;
+
+    /// How many times we've connected again, after the first time.
+    fn reconnects(&self) -> u64;
+
+    /// Drop the connection, if any, and connect again from scratch.
+    fn reconnect(&self) -> BoxFuture<'_, anyhow::Result<()>>;
+
+    /// Every characteristic on the ornament's own service, or `None` if we're
+    /// not connected.
+    fn characteristics(&self) -> Option<Vec<CharacteristicInfo>>;
+}
+
+/// The names of the characteristic properties, as reported by
+/// `OrnamentTransport::characteristics`.
+static PROPERTY_NAMES: [(CharPropFlags, &str); 8] = [
+    (CharPropFlags::BROADCAST, "broadcast"),
+    (CharPropFlags::READ, "read"),
+    (
+        CharPropFlags::WRITE_WITHOUT_RESPONSE,
+        "write_without_response",
+    ),
+    (CharPropFlags::WRITE, "write"),
+    (CharPropFlags::NOTIFY, "notify"),
+    (CharPropFlags::INDICATE, "indicate"),
+    (
+        CharPropFlags::AUTHENTICATED_SIGNED_WRITES,
+        "authenticated_signed_writes",
+    ),
+    (CharPropFlags::EXTENDED_PROPERTIES, "extended_properties"),
+];
+
+/// Talks to the ornament over BLE, through whatever the current `connection`
+/// is. The `connector` is for when the ornament moves.
+pub struct BleTransport {
+    connection: SharedConnection,
+    connector: Arc<Connector>,
+}
+
+impl BleTransport {
+    pub fn new(connection: SharedConnection, connector: Arc<Connector>) -> Self {
+        BleTransport {
+            connection,
+            connector,
+        }
+    }
+
+    /// Get the current connection, or the error to return if we don't have
+    /// one.
+    fn connection(&self) -> Result<Connection, TransportError> {
+        self.connection
+            .get()
+            .ok_or_else(|| TransportError::NotConnected {
+                retry_after: self.connection.retry_after().unwrap_or_default(),
+            })
+    }
+
+    /// Get the current connection along with the characteristic with the given
+    /// `uuid` on it. Standard characteristics are only looked for on their own
+    /// service. See `ble::find_characteristic_on`.
+    fn find(&self, uuid: CharUuid) -> Result<(Connection, Characteristic), TransportError> {
+        let connection = self.connection()?;
+        let characteristic =
+            ble::find_characteristic_on(&connection.service, &connection.standard, uuid)
+                .ok_or(TransportError::NotFound)?
+                .clone();
+        Ok((connection, characteristic))
+    }
+}
+
+impl OrnamentTransport for BleTransport {
+    fn read(&self, uuid: CharUuid) -> BoxFuture<'_, Result<Vec<u8>, TransportError>> {
+        Box::pin(async move {
+            let (connection, characteristic) = self.find(uuid)?;
+            ble::read_characteristic(&connection.peripheral, &characteristic)
+                .await
+                .map_err(TransportError::Failed)
+        })
+    }
+
+    fn write<'a>(
+        &'a self,
+        uuid: CharUuid,
+        value: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), TransportError>> {
+        Box::pin(async move {
+            let (connection, characteristic) = self.find(uuid)?;
+            let write_type = ble::write_type(&characteristic);
+            log::debug!("    writing with {:?}", write_type);
+            ble::write_characteristic(&connection.peripheral, &characteristic, value, write_type)
+                .await
+                .map_err(TransportError::Failed)
+        })
+    }
+
+    fn state(&self) -> BoxFuture<'_, ConnectionState> {
+        Box::pin(async move {
+            match self.connection.get() {
+                Some(c) if c.peripheral.is_connected().await.unwrap_or(false) => {
+                    ConnectionState::Connected
+                }
+                Some(_) => ConnectionState::Disconnected,
+                None => ConnectionState::Reconnecting,
+            }
+        })
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        self.connection.retry_after()
+    }
+
+    fn reconnects(&self) -> u64 {
+        self.connection.reconnects()
+    }
+
+    fn reconnect(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move { self.connector.rescan(&self.connection).await.map(|_| ()) })
+    }
+
+    fn characteristics(&self) -> Option<Vec<CharacteristicInfo>> {
+        let connection = self.connection.get()?;
+        let characteristics = connection
+            .service
+            .characteristics
+            .iter()
+            .map(|c| CharacteristicInfo {
+                uuid: c.uuid,
+                properties: PROPERTY_NAMES
+                    .iter()
+                    .filter(|(flag, _)| c.properties.contains(*flag))
+                    .map(|(_, name)| *name)
+                    .collect(),
+            })
+            .collect();
+        Some(characteristics)
+    }
+}
+
+#[cfg(test)]
+pub use mock::MockTransport;
+
+#[cfg(test)]
+mod mock {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// An ornament that only exists in memory. Characteristics hold whatever
+    /// was last `set` or written to them. Writes can be redirected to another
+    /// characteristic with `alias`, like the ornament does for its
+    /// configuration characteristics. Reads always return the whole value, like
+    /// the OS does over BLE.
+    #[derive(Default)]
+    pub struct MockTransport {
+        values: Mutex<HashMap<CharUuid, Vec<u8>>>,
+        aliases: Mutex<HashMap<CharUuid, CharUuid>>,
+        disconnected: Mutex<bool>,
+    }
+
+    impl MockTransport {
+        /// Give the characteristic `uuid` the `value`.
+        pub fn set(&self, uuid: CharUuid, value: &[u8]) {
+            self.values.lock().unwrap().insert(uuid, value.to_vec());
+        }
+
+        /// Take the characteristic `uuid` away, like firmware that doesn't
+        /// have it.
+        pub fn remove(&self, uuid: CharUuid) {
+            self.values.lock().unwrap().remove(&uuid);
+        }
+
+        /// Get the characteristic `uuid`'s value, if it has one.
+        pub fn value(&self, uuid: CharUuid) -> Option<Vec<u8>> {
+            self.values.lock().unwrap().get(&uuid).cloned()
+        }
+
+        /// Make writes to `from` change `to` instead.
+        pub fn alias(&self, from: CharUuid, to: CharUuid) {
+            self.aliases.lock().unwrap().insert(from, to);
+        }
+
+        /// Pretend the ornament dropped off, or came back.
+        pub fn set_connected(&self, connected: bool) {
+            *self.disconnected.lock().unwrap() = !connected;
+        }
+
+        fn check_connected(&self) -> Result<(), TransportError> {
+            match *self.disconnected.lock().unwrap() {
+                true => Err(TransportError::NotConnected {
+                    retry_after: Duration::from_secs(1),
+                }),
+                false => Ok(()),
+            }
+        }
+    }
+
+    impl OrnamentTransport for MockTransport {
+        fn read(&self, uuid: CharUuid) -> BoxFuture<'_, Result<Vec<u8>, TransportError>> {
+            Box::pin(async move {
+                self.check_connected()?;
+                self.value(uuid).ok_or(TransportError::NotFound)
+            })
+        }
+
+        fn write<'a>(
+            &'a self,
+            uuid: CharUuid,
+            value: &'a [u8],
+        ) -> BoxFuture<'a, Result<(), TransportError>> {
+            Box::pin(async move {
+                self.check_connected()?;
+                let target = self.aliases.lock().unwrap().get(&uuid).copied();
+                let target = target.unwrap_or(uuid);
+                if self.value(target).is_none() {
+                    return Err(TransportError::NotFound);
+                }
+                self.set(target, value);
+                Ok(())
+            })
+        }
+
+        fn state(&self) -> BoxFuture<'_, ConnectionState> {
+            Box::pin(async move {
+                match self.check_connected() {
+                    Ok(()) => ConnectionState::Connected,
+                    Err(_) => ConnectionState::Reconnecting,
+                }
+            })
+        }
+
+        fn retry_after(&self) -> Option<Duration> {
+            match self.check_connected() {
+                Ok(()) => None,
+                Err(_) => Some(Duration::from_secs(1)),
+            }
+        }
+
+        fn reconnects(&self) -> u64 {
+            0
+        }
+
+        fn reconnect(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+            Box::pin(async move {
+                self.set_connected(true);
+                Ok(())
+            })
+        }
+
+        fn characteristics(&self) -> Option<Vec<CharacteristicInfo>> {
+            self.check_connected().ok()?;
+            let values = self.values.lock().unwrap();
+            Some(
+                values
+                    .keys()
+                    .map(|uuid| CharacteristicInfo {
+                        uuid: uuid.uuid(),
+                        properties: vec!["read"],
+                    })
+                    .collect(),
+            )
+        }
+    }
+}